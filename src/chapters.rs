@@ -0,0 +1,159 @@
+use std::time::Duration;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Chapter {
+    pub start: Duration,
+    pub name: Option<String>,
+}
+
+/// Parse a chapter timestamp of the form "HH:MM:SS.mmm" (hours and fraction are optional).
+fn parse_timestamp(s: &str) -> Option<Duration> {
+    let (hms, fraction) = match s.find('.') {
+        Some(i) => (&s[..i], &s[i + 1..]),
+        None => (s, ""),
+    };
+    let mut secs = 0u64;
+    let mut parts = 0;
+    for part in hms.split(':') {
+        if part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        // The tags come from the media files, so huge numbers must not overflow.
+        secs = secs.checked_mul(60)?.checked_add(part.parse().ok()?)?;
+        parts += 1;
+    }
+    if parts > 3 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let mut nanos = 0u32;
+    for (i, d) in fraction.bytes().take(9).enumerate() {
+        nanos += (d - b'0') as u32 * 10u32.pow(8 - i as u32);
+    }
+    Some(Duration::new(secs, nanos))
+}
+
+/// Split a "CHAPTERxxx" or "CHAPTERxxxNAME" key into the chapter number and whether it is the
+/// name.
+fn parse_chapter_key(key: &str) -> Option<(u32, bool)> {
+    let key = key.to_ascii_uppercase();
+    let rest = key.strip_prefix("CHAPTER")?;
+    let (num, is_name) = match rest.strip_suffix("NAME") {
+        Some(num) => (num, true),
+        None => (rest, false),
+    };
+    if num.is_empty() || !num.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some((num.parse().ok()?, is_name))
+}
+
+/// Extract the chapters from vorbis comments ("CHAPTER001=00:00:00.000",
+/// "CHAPTER001NAME=Introduction"). The result is ordered by start time.
+pub fn parse_chapters(comments: &[(String, String)]) -> Vec<Chapter> {
+    let mut chapters = std::collections::BTreeMap::new();
+    for (key, value) in comments {
+        match parse_chapter_key(key) {
+            Some((num, false)) => {
+                if let Some(start) = parse_timestamp(value.trim()) {
+                    chapters.entry(num).or_insert((None, None)).0 = Some(start);
+                }
+            }
+            Some((num, true)) => {
+                chapters.entry(num).or_insert((None, None)).1 = Some(value.clone());
+            }
+            None => {}
+        }
+    }
+    let mut chapters = chapters
+        .into_iter()
+        .filter_map(|(_, (start, name))| {
+            Some(Chapter {
+                start: start?,
+                name,
+            })
+        })
+        .collect::<Vec<_>>();
+    chapters.sort_by_key(|c| c.start);
+    chapters
+}
+
+/// Index of the chapter that contains pos.
+pub fn chapter_at(chapters: &[Chapter], pos: Duration) -> Option<usize> {
+    chapters.iter().rposition(|c| c.start <= pos)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn comments(c: &[(&str, &str)]) -> Vec<(String, String)> {
+        c.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_timestamp() {
+        assert_eq!(
+            parse_timestamp("01:02:03.500"),
+            Some(Duration::from_millis(3_723_500))
+        );
+        assert_eq!(parse_timestamp("02:03"), Some(Duration::from_secs(123)));
+        assert_eq!(parse_timestamp("00:00:00"), Some(Duration::from_secs(0)));
+        assert_eq!(
+            parse_timestamp("00:00:01.05"),
+            Some(Duration::from_millis(1050))
+        );
+        assert_eq!(parse_timestamp(""), None);
+        assert_eq!(parse_timestamp("1:2:3:4"), None);
+        assert_eq!(parse_timestamp("00:-1:00"), None);
+        assert_eq!(parse_timestamp("00:01.5x"), None);
+        assert_eq!(parse_timestamp(&format!("{}:00:00", u64::MAX / 60)), None);
+        assert_eq!(parse_timestamp("99999999999999999999:00"), None);
+    }
+
+    #[test]
+    fn test_parse_chapters() {
+        let c = comments(&[
+            ("TITLE", "Momo"),
+            ("CHAPTER002", "00:10:00.000"),
+            ("CHAPTER002NAME", "Second"),
+            ("chapter001", "00:00:00.000"),
+            ("Chapter001Name", "First"),
+            ("CHAPTER003", "00:20:00.000"),
+            ("CHAPTER004NAME", "No start"),
+            ("CHAPTER005", "garbage"),
+            ("CHAPTERS", "00:30:00"),
+        ]);
+        assert_eq!(
+            parse_chapters(&c),
+            vec![
+                Chapter {
+                    start: Duration::from_secs(0),
+                    name: Some("First".to_owned())
+                },
+                Chapter {
+                    start: Duration::from_secs(600),
+                    name: Some("Second".to_owned())
+                },
+                Chapter {
+                    start: Duration::from_secs(1200),
+                    name: None
+                },
+            ]
+        );
+        assert!(parse_chapters(&comments(&[("ARTIST", "Ende")])).is_empty());
+    }
+
+    #[test]
+    fn test_chapter_at() {
+        let chapters = parse_chapters(&comments(&[
+            ("CHAPTER001", "00:00:10"),
+            ("CHAPTER002", "00:01:00"),
+        ]));
+        assert_eq!(chapter_at(&chapters, Duration::from_secs(5)), None);
+        assert_eq!(chapter_at(&chapters, Duration::from_secs(10)), Some(0));
+        assert_eq!(chapter_at(&chapters, Duration::from_secs(59)), Some(0));
+        assert_eq!(chapter_at(&chapters, Duration::from_secs(3600)), Some(1));
+    }
+}
@@ -1,14 +1,65 @@
+use crate::fade::FadeCurve;
+use crate::filter::FilterSpec;
+use crate::resampler::Algorithm;
 use std::time::Duration;
 
 pub const MIN_TIME_FOR_CONTEXT: Duration = Duration::from_secs(10);
 pub const MAX_CONTEXT_TIME: Duration = Duration::from_secs(60);
 pub const PAUSE_TO_CONTEXT_RATIO: u32 = 10;
 pub const FADE_TIME: Duration = Duration::from_millis(500);
+/// Shape of the fades when playback starts and stops (unless set for the media).
+pub const FADE_CURVE: FadeCurve = FadeCurve::EqualPower;
+/// Time over which a volume change is spread to avoid clicks.
+pub const VOLUME_SMOOTHING_TIME: Duration = Duration::from_millis(50);
 pub const AUDIO_BUF_SIZE: Duration = Duration::from_millis(100);
+/// Conversion of tracks to the sample rate of the output. Linear is cheaper, but less accurate.
+pub const RESAMPLE_ALGORITHM: Algorithm = Algorithm::Sinc;
+/// Amount of audio that is decoded ahead of the output.
+pub const PREFETCH_TIME: Duration = Duration::from_secs(5);
+/// Time that the output waits for the decoder before it plays silence instead.
+pub const PREFETCH_WAIT_TIME: Duration = Duration::from_millis(50);
+/// Interval at which the decoder checks for free space in a full prefetch buffer.
+pub const PREFETCH_POLL_INTERVAL: Duration = Duration::from_millis(10);
 pub const IDLE_SLEEP_TIME: Duration = Duration::from_secs(10 * 60);
-pub const DEFAULT_VOLUME: u8 = 11;
+/// Number of audible volume levels (i.e., steps of the rotary encoder above silence)
+pub const VOLUME_STEPS: u8 = 24;
+/// Gain (in dB) of the lowest audible volume level
+pub const MIN_VOLUME_DB: f32 = -46.0;
+/// Gain (in dB) of the highest volume level
+pub const MAX_VOLUME_DB: f32 = 0.0;
+pub const DEFAULT_VOLUME: u8 = 12;
+/// Sample peak (in dBFS) that the output never exceeds, whatever the volume and the media are
+pub const MAX_OUTPUT_PEAK: f32 = -1.0;
+/// Short-term loudness (in LUFS) that the output is kept below
+pub const MAX_OUTPUT_LOUDNESS: f32 = -14.0;
+/// Processing of the output (before the limiter), unless the media definition has an [output]
+/// section. The speaker distorts below 150Hz at high volume.
+pub const FILTERS: &[FilterSpec] = &[FilterSpec::HighPass { frequency: 150.0 }];
+/// Loudness (in LUFS) that all tracks are normalized to. This is the reference level of
+/// ReplayGain 2.0.
+pub const TARGET_LOUDNESS: f32 = -18.0;
+/// Upper bound for the gain (in dB) that is applied to quiet tracks.
+pub const MAX_TRACK_GAIN: f32 = 12.0;
+/// Nice value of the thread that measures the loudness of tracks in the background. The highest
+/// value (19) leaves the cpu to the playback whenever it needs it.
+pub const ANALYZER_NICENESS: i32 = 19;
+/// Decoding a track is given up after this many consecutive errors.
+pub const MAX_DECODE_RETRIES: u32 = 8;
+/// Distance that is skipped after a decode error. It is doubled with every further error.
+pub const DECODE_ERROR_SKIP: Duration = Duration::from_millis(100);
+/// Number of cards whose playback position is remembered. The least recently played ones are
+/// forgotten first.
+pub const MAX_RESUME_POINTS: usize = 64;
+/// Playback positions of cards that have not been played for this long are forgotten.
+pub const MAX_RESUME_POINT_AGE: Duration = Duration::from_secs(90 * 24 * 60 * 60);
+/// Interval at which the media definition file is checked for changes if inotify is unavailable.
+pub const FILE_WATCH_POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// Going to the previous chapter restarts the current one instead if it has been playing for
+/// longer than this.
+pub const CHAPTER_RESTART_TIME: Duration = Duration::from_secs(3);
 
 pub const DATA_MOUNT_PATH: &str = "/data";
 pub const MEDIA_DEFINITION_FILE: &str = "media_definition.txt";
 pub const SAVESTATE_FILE: &str = "savestate.json";
 pub const LOG_FILE: &str = "kassette.log";
+pub const INBOX_DIR: &str = "inbox";
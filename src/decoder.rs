@@ -0,0 +1,286 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+#[cfg(feature = "flac")]
+mod flac;
+#[cfg(feature = "mp3")]
+mod mp3;
+mod ogg_page;
+#[cfg(feature = "opus")]
+mod opus;
+#[cfg(any(feature = "mp3", feature = "flac"))]
+mod symphonia;
+mod vorbis;
+mod wav;
+
+#[cfg(test)]
+pub use vorbis::test::vorbis_file;
+
+/// Properties of an audio stream as found in the header of the file.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct StreamInfo {
+    pub sample_rate: u32,
+    pub channels: usize,
+}
+
+/// Order of the channels in a frame with more than two channels.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ChannelOrder {
+    /// As defined by the vorbis specification: front left, center, front right, ...
+    Vorbis,
+    /// As in the channel mask of wav files: front left, front right, center, lfe, ...
+    Wave,
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    Io(std::io::Error),
+    Vorbis(lewton::VorbisError),
+    #[cfg(any(feature = "mp3", feature = "flac"))]
+    Symphonia(symphonia_core::errors::Error),
+    #[cfg(feature = "opus")]
+    Ogg(ogg::OggReadError),
+    #[cfg(feature = "opus")]
+    Opus(audiopus::Error),
+    UnsupportedFormat,
+}
+
+impl From<std::io::Error> for DecodeError {
+    fn from(e: std::io::Error) -> Self {
+        DecodeError::Io(e)
+    }
+}
+
+impl From<lewton::VorbisError> for DecodeError {
+    fn from(e: lewton::VorbisError) -> Self {
+        DecodeError::Vorbis(e)
+    }
+}
+
+#[cfg(any(feature = "mp3", feature = "flac"))]
+impl From<symphonia_core::errors::Error> for DecodeError {
+    fn from(e: symphonia_core::errors::Error) -> Self {
+        DecodeError::Symphonia(e)
+    }
+}
+
+#[cfg(feature = "opus")]
+impl From<ogg::OggReadError> for DecodeError {
+    fn from(e: ogg::OggReadError) -> Self {
+        DecodeError::Ogg(e)
+    }
+}
+
+#[cfg(feature = "opus")]
+impl From<audiopus::Error> for DecodeError {
+    fn from(e: audiopus::Error) -> Self {
+        DecodeError::Opus(e)
+    }
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::Io(e) => write!(f, "I/O error: {}", e),
+            DecodeError::Vorbis(e) => write!(f, "Vorbis error: {}", e),
+            #[cfg(any(feature = "mp3", feature = "flac"))]
+            DecodeError::Symphonia(e) => write!(f, "Decoding error: {}", e),
+            #[cfg(feature = "opus")]
+            DecodeError::Ogg(e) => write!(f, "Ogg error: {}", e),
+            #[cfg(feature = "opus")]
+            DecodeError::Opus(e) => write!(f, "Opus error: {}", e),
+            DecodeError::UnsupportedFormat => write!(f, "Unsupported format"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DecodeError::Io(e) => Some(e),
+            DecodeError::Vorbis(e) => Some(e),
+            #[cfg(any(feature = "mp3", feature = "flac"))]
+            DecodeError::Symphonia(e) => Some(e),
+            #[cfg(feature = "opus")]
+            DecodeError::Ogg(e) => Some(e),
+            #[cfg(feature = "opus")]
+            DecodeError::Opus(e) => Some(e),
+            DecodeError::UnsupportedFormat => None,
+        }
+    }
+}
+
+/// A source of audio samples for a single file. All positions are in frames, i.e., a single
+/// sample for every channel.
+pub trait Decoder: Send {
+    fn info(&self) -> StreamInfo;
+
+    /// Decode the next packet of the stream into interleaved samples. Returns None at the end of
+    /// the stream. A packet may be empty.
+    fn next_packet(&mut self) -> Result<Option<Vec<i16>>, DecodeError>;
+
+    /// Continue decoding at the given frame.
+    fn seek(&mut self, frame: u64) -> Result<(), DecodeError>;
+
+    /// The frame that the next packet starts at.
+    fn position(&self) -> u64;
+
+    /// Total number of frames of the stream, if known.
+    fn duration(&self) -> Option<u64>;
+
+    fn channel_order(&self) -> ChannelOrder {
+        ChannelOrder::Vorbis
+    }
+
+    /// Metadata tags of the stream as (key, value) pairs in the style of vorbis comments.
+    fn comments(&self) -> &[(String, String)] {
+        &[]
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Format {
+    Vorbis,
+    // Recognized even without opus support to produce a sensible error.
+    Opus,
+    #[cfg(feature = "mp3")]
+    Mp3,
+    #[cfg(feature = "flac")]
+    Flac,
+    Wav,
+}
+
+impl Format {
+    fn from_extension(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "ogg" | "oga" => Some(Format::Vorbis),
+            #[cfg(feature = "opus")]
+            "opus" => Some(Format::Opus),
+            #[cfg(feature = "mp3")]
+            "mp3" => Some(Format::Mp3),
+            #[cfg(feature = "flac")]
+            "flac" => Some(Format::Flac),
+            "wav" => Some(Format::Wav),
+            _ => None,
+        }
+    }
+
+    fn from_magic(header: &[u8]) -> Option<Self> {
+        match header {
+            // The first page of the ogg stream contains the identification header of the codec.
+            [b'O', b'g', b'g', b'S', ..] if header.windows(8).any(|w| w == b"OpusHead") => {
+                Some(Format::Opus)
+            }
+            [b'O', b'g', b'g', b'S', ..] => Some(Format::Vorbis),
+            [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'A', b'V', b'E', ..] => Some(Format::Wav),
+            #[cfg(feature = "flac")]
+            [b'f', b'L', b'a', b'C', ..] => Some(Format::Flac),
+            // Either an ID3v2 tag or the sync word of an mpeg audio frame
+            #[cfg(feature = "mp3")]
+            [b'I', b'D', b'3', ..] => Some(Format::Mp3),
+            #[cfg(feature = "mp3")]
+            [0xff, b, ..] if b & 0xe0 == 0xe0 => Some(Format::Mp3),
+            _ => None,
+        }
+    }
+}
+
+/// Check whether the file looks like something we are able to decode.
+pub fn is_playable(file_path: impl AsRef<Path>) -> bool {
+    Format::from_extension(file_path.as_ref()).is_some()
+}
+
+/// Open the file with the decoder for its format. The format is determined by the content of the
+/// file if possible and by its extension otherwise.
+pub fn open(file_path: impl AsRef<Path>) -> Result<Box<dyn Decoder>, DecodeError> {
+    let file_path = file_path.as_ref();
+    let mut f = File::open(file_path)?;
+
+    let mut header = Vec::new();
+    (&mut f).take(64).read_to_end(&mut header)?;
+    f.seek(SeekFrom::Start(0))?;
+
+    let format = Format::from_magic(&header)
+        .or_else(|| Format::from_extension(file_path))
+        .ok_or(DecodeError::UnsupportedFormat)?;
+
+    Ok(match format {
+        Format::Vorbis => Box::new(vorbis::VorbisDecoder::new(f)?),
+        #[cfg(feature = "opus")]
+        Format::Opus => Box::new(opus::OpusDecoder::new(f)?),
+        #[cfg(not(feature = "opus"))]
+        Format::Opus => return Err(DecodeError::UnsupportedFormat),
+        #[cfg(feature = "mp3")]
+        Format::Mp3 => Box::new(mp3::open(f)?),
+        #[cfg(feature = "flac")]
+        Format::Flac => Box::new(flac::open(f)?),
+        Format::Wav => Box::new(wav::WavDecoder::new(f)?),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_format_detection() {
+        assert_eq!(
+            Format::from_extension(Path::new("a/b.OGG")),
+            Some(Format::Vorbis)
+        );
+        assert_eq!(Format::from_extension(Path::new("a/ogg")), None);
+        assert_eq!(Format::from_extension(Path::new("cover.jpg")), None);
+        assert_eq!(Format::from_magic(b"OggS\0\x02"), Some(Format::Vorbis));
+        assert_eq!(Format::from_magic(b"Og"), None);
+        assert_eq!(
+            Format::from_magic(b"OggS\0\x02\0\0\0\0\0\0\0\0\x01\x02\x03\x04\0\0\0\0\0\0\0\0\x01\x13OpusHead\x01\x02"),
+            Some(Format::Opus)
+        );
+        assert_eq!(Format::from_magic(b"RIFF"), None);
+        assert_eq!(Format::from_magic(b"RIFF\0\0\0\0AVI "), None);
+        assert_eq!(
+            Format::from_magic(b"RIFF\x24\0\0\0WAVEfmt "),
+            Some(Format::Wav)
+        );
+        assert_eq!(
+            Format::from_extension(Path::new("memo.WAV")),
+            Some(Format::Wav)
+        );
+
+        #[cfg(feature = "mp3")]
+        {
+            assert_eq!(
+                Format::from_extension(Path::new("chapter 1.mp3")),
+                Some(Format::Mp3)
+            );
+            assert_eq!(Format::from_magic(b"ID3\x04"), Some(Format::Mp3));
+            assert_eq!(
+                Format::from_magic(&[0xff, 0xfb, 0x90, 0x64]),
+                Some(Format::Mp3)
+            );
+            assert_eq!(Format::from_magic(&[0xff, 0x00]), None);
+        }
+
+        #[cfg(feature = "flac")]
+        {
+            assert_eq!(
+                Format::from_extension(Path::new("01 Track.flac")),
+                Some(Format::Flac)
+            );
+            assert_eq!(Format::from_magic(b"fLaC\0\0\0\x22"), Some(Format::Flac));
+        }
+    }
+
+    #[test]
+    fn test_open_by_content() {
+        // The extension is misleading, but the content decides.
+        let path = std::env::temp_dir().join(format!("kassette_open_{}.ogg", std::process::id()));
+        std::fs::write(&path, wav::test::wav_file(2, 2, &[1, 2, 3, 4])).unwrap();
+        let mut decoder = open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(decoder.info().channels, 2);
+        assert_eq!(decoder.next_packet().unwrap(), Some(vec![1, 2, 3, 4]));
+    }
+}
@@ -0,0 +1,8 @@
+use super::symphonia::SymphoniaDecoder;
+use super::DecodeError;
+use std::fs::File;
+use symphonia_bundle_mp3::{MpaDecoder, MpaReader};
+
+pub fn open(f: File) -> Result<SymphoniaDecoder, DecodeError> {
+    SymphoniaDecoder::new::<MpaReader, MpaDecoder>(f)
+}
@@ -0,0 +1,54 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+
+/// The granule position of the last ogg page in the data.
+fn last_granule_position(data: &[u8]) -> Option<u64> {
+    let page_start = data.windows(4).rposition(|w| w == b"OggS")?;
+    let granule = data.get(page_start + 6..page_start + 14)?;
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(granule);
+    match u64::from_le_bytes(bytes) {
+        // No packet finishes on this page.
+        u64::MAX => None,
+        g => Some(g),
+    }
+}
+
+/// The granule position of the last page of the file, which denotes the length of the stream
+/// (in a codec specific way). The file is rewound to the start afterwards.
+pub fn end_granule_position(f: &mut File) -> std::io::Result<Option<u64>> {
+    const MAX_PAGE_SIZE: u64 = 65307;
+
+    let len = f.seek(SeekFrom::End(0))?;
+    f.seek(SeekFrom::Start(len.saturating_sub(MAX_PAGE_SIZE)))?;
+    let mut tail = Vec::new();
+    f.read_to_end(&mut tail)?;
+    f.seek(SeekFrom::Start(0))?;
+    Ok(last_granule_position(&tail))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn page(granule: u64) -> Vec<u8> {
+        let mut page = b"OggS\0\x04".to_vec();
+        page.extend_from_slice(&granule.to_le_bytes());
+        page.extend_from_slice(&[0; 13]);
+        page
+    }
+
+    #[test]
+    fn test_last_granule_position() {
+        let mut data = page(1000);
+        data.extend_from_slice(b"audio data");
+        data.extend(page(44100 * 60));
+        assert_eq!(last_granule_position(&data), Some(44100 * 60));
+
+        data.extend(page(u64::MAX));
+        assert_eq!(last_granule_position(&data), None);
+
+        assert_eq!(last_granule_position(b"OggS\0"), None);
+        assert_eq!(last_granule_position(b"no pages"), None);
+    }
+}
@@ -0,0 +1,284 @@
+use super::{ogg_page, DecodeError, Decoder, StreamInfo};
+use audiopus::coder::{Decoder as PacketDecoder, GenericCtl};
+use audiopus::{Channels, MutSignals, SampleRate};
+use ogg::{Packet, PacketReader};
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::fs::File;
+
+/// Opus is always decoded at 48kHz. Granule positions are in units of this rate, too.
+const SAMPLE_RATE: u32 = 48000;
+/// Maximum duration of an opus packet (120ms).
+const MAX_PACKET_FRAMES: usize = 5760;
+/// Decoding should start this many frames before a seek target so that the decoder has converged.
+const SEEK_PREROLL: u64 = 3840;
+
+/// The identification header of an ogg opus stream.
+#[derive(Debug, PartialEq, Eq)]
+struct OpusHead {
+    channels: u8,
+    pre_skip: u16,
+    output_gain: i16,
+    mapping_family: u8,
+}
+
+impl OpusHead {
+    fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < 19 || !data.starts_with(b"OpusHead") {
+            return None;
+        }
+        Some(OpusHead {
+            channels: data[9],
+            pre_skip: u16::from_le_bytes([data[10], data[11]]),
+            output_gain: i16::from_le_bytes([data[16], data[17]]),
+            mapping_family: data[18],
+        })
+    }
+}
+
+/// Parse the comment header of an ogg opus stream into (key, value) pairs. The layout is the same
+/// as that of vorbis comments, but without the framing bit.
+fn parse_opus_tags(data: &[u8]) -> Option<Vec<(String, String)>> {
+    fn read_u32(data: &mut &[u8]) -> Option<u32> {
+        let bytes = data.get(..4)?;
+        let value = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        *data = &data[4..];
+        Some(value)
+    }
+
+    let mut data = data.strip_prefix(b"OpusTags")?;
+    let vendor_len = read_u32(&mut data)? as usize;
+    data = data.get(vendor_len..)?;
+    let count = read_u32(&mut data)?;
+    let mut comments = Vec::new();
+    for _ in 0..count {
+        let len = read_u32(&mut data)? as usize;
+        let comment = String::from_utf8_lossy(data.get(..len)?);
+        data = &data[len..];
+        if let Some(i) = comment.find('=') {
+            comments.push((comment[..i].to_owned(), comment[i + 1..].to_owned()));
+        }
+    }
+    Some(comments)
+}
+
+pub struct OpusDecoder {
+    reader: PacketReader<File>,
+    decoder: PacketDecoder,
+    serial: u32,
+    channels: usize,
+    pre_skip: u64,
+    duration: Option<u64>,
+    comments: Vec<(String, String)>,
+    position: u64,
+    // Decoded frames that are dropped before they are returned, i.e., the pre-skip at the
+    // beginning of the stream and the frames before the target after seeking.
+    skip: u64,
+    // Packets that have already been read from the stream while seeking.
+    pending: VecDeque<Packet>,
+    finished: bool,
+}
+
+fn invalid() -> DecodeError {
+    DecodeError::Io(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        "invalid opus header",
+    ))
+}
+
+impl OpusDecoder {
+    pub fn new(mut f: File) -> Result<Self, DecodeError> {
+        let end_granule = ogg_page::end_granule_position(&mut f)?;
+        let mut reader = PacketReader::new(f);
+
+        let head_packet = reader.read_packet_expected()?;
+        let head = OpusHead::parse(&head_packet.data).ok_or_else(invalid)?;
+        let channels = match (head.mapping_family, head.channels) {
+            (0, 1) => Channels::Mono,
+            (0, 2) => Channels::Stereo,
+            _ => return Err(DecodeError::UnsupportedFormat),
+        };
+        let tags = reader.read_packet_expected()?;
+        let comments = parse_opus_tags(&tags.data).ok_or_else(invalid)?;
+
+        let decoder = PacketDecoder::new(SampleRate::Hz48000, channels)?;
+        decoder.set_gain(head.output_gain as i32)?;
+
+        let pre_skip = head.pre_skip as u64;
+        Ok(OpusDecoder {
+            reader,
+            decoder,
+            serial: head_packet.stream_serial(),
+            channels: head.channels as usize,
+            pre_skip,
+            duration: end_granule.map(|g| g.saturating_sub(pre_skip)),
+            comments,
+            position: 0,
+            skip: pre_skip,
+            pending: VecDeque::new(),
+            finished: false,
+        })
+    }
+
+    fn next_stream_packet(&mut self) -> Result<Option<Packet>, DecodeError> {
+        if let Some(packet) = self.pending.pop_front() {
+            return Ok(Some(packet));
+        }
+        loop {
+            match self.reader.read_packet()? {
+                Some(packet) if packet.stream_serial() == self.serial => return Ok(Some(packet)),
+                Some(_) => {}
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Number of frames that the packet decodes to.
+    fn packet_frames(packet: &Packet) -> Result<u64, DecodeError> {
+        if packet.data.is_empty() {
+            return Ok(0);
+        }
+        let packet = audiopus::packet::Packet::try_from(&packet.data[..])?;
+        Ok(audiopus::packet::nb_samples(packet, SampleRate::Hz48000)? as u64)
+    }
+}
+
+impl Decoder for OpusDecoder {
+    fn info(&self) -> StreamInfo {
+        StreamInfo {
+            sample_rate: SAMPLE_RATE,
+            channels: self.channels,
+        }
+    }
+
+    fn next_packet(&mut self) -> Result<Option<Vec<i16>>, DecodeError> {
+        if self.finished {
+            return Ok(None);
+        }
+        let packet = match self.next_stream_packet()? {
+            Some(packet) => packet,
+            None => return Ok(None),
+        };
+        // Empty packets carry no audio (e.g., discontinuous transmission).
+        if packet.data.is_empty() {
+            return Ok(Some(Vec::new()));
+        }
+
+        let mut buf = vec![0i16; MAX_PACKET_FRAMES * self.channels];
+        let frames = self.decoder.decode(
+            Some(audiopus::packet::Packet::try_from(&packet.data[..])?),
+            MutSignals::try_from(&mut buf[..])?,
+            false,
+        )? as u64;
+
+        let skip = self.skip.min(frames);
+        self.skip -= skip;
+        let mut end = frames;
+        if packet.last_in_stream() {
+            // The last packet may be padded, the granule position tells where the stream ends.
+            let stream_end = packet.absgp_page().saturating_sub(self.pre_skip);
+            end = end.min(skip + stream_end.saturating_sub(self.position));
+            self.finished = true;
+        }
+        let end = end.max(skip);
+        self.position += end - skip;
+        buf.truncate(end as usize * self.channels);
+        buf.drain(..skip as usize * self.channels);
+        Ok(Some(buf))
+    }
+
+    fn seek(&mut self, frame: u64) -> Result<(), DecodeError> {
+        self.pending.clear();
+        self.finished = false;
+        self.decoder.reset_state()?;
+
+        let target = frame + self.pre_skip;
+        let goal = target.saturating_sub(SEEK_PREROLL);
+        if !self.reader.seek_absgp(Some(self.serial), goal)? {
+            // Beyond the end of the stream
+            self.finished = true;
+            self.position = frame;
+            return Ok(());
+        }
+
+        // We only know the granule position at the end of a page, so we read up to there to find
+        // out where the first packet starts.
+        let mut frames = 0;
+        let page_end = loop {
+            let packet = match self.next_stream_packet()? {
+                Some(packet) => packet,
+                None => break None,
+            };
+            frames += Self::packet_frames(&packet)?;
+            let last_in_page = packet.last_in_page();
+            let page_end = packet.absgp_page();
+            self.pending.push_back(packet);
+            if last_in_page {
+                break Some(page_end);
+            }
+        };
+        let start = page_end.map(|e| e.saturating_sub(frames)).unwrap_or(0);
+
+        self.skip = target.saturating_sub(start);
+        self.position = frame;
+        Ok(())
+    }
+
+    fn position(&self) -> u64 {
+        self.position
+    }
+
+    fn duration(&self) -> Option<u64> {
+        self.duration
+    }
+
+    fn comments(&self) -> &[(String, String)] {
+        &self.comments
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_opus_head() {
+        let mut head = b"OpusHead\x01\x02".to_vec();
+        head.extend_from_slice(&312u16.to_le_bytes());
+        head.extend_from_slice(&44100u32.to_le_bytes());
+        head.extend_from_slice(&(-256i16).to_le_bytes());
+        head.push(0);
+        assert_eq!(
+            OpusHead::parse(&head),
+            Some(OpusHead {
+                channels: 2,
+                pre_skip: 312,
+                output_gain: -256,
+                mapping_family: 0,
+            })
+        );
+        assert_eq!(OpusHead::parse(&head[..18]), None);
+        assert_eq!(OpusHead::parse(b"\x01vorbis\0\0\0\0\0\0\0\0\0\0\0\0"), None);
+    }
+
+    #[test]
+    fn test_parse_opus_tags() {
+        let mut tags = b"OpusTags".to_vec();
+        tags.extend_from_slice(&5u32.to_le_bytes());
+        tags.extend_from_slice(b"libop");
+        tags.extend_from_slice(&2u32.to_le_bytes());
+        for comment in &["TITLE=Momo", "CHAPTER001=00:00:00.000"] {
+            tags.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+            tags.extend_from_slice(comment.as_bytes());
+        }
+        assert_eq!(
+            parse_opus_tags(&tags),
+            Some(vec![
+                ("TITLE".to_owned(), "Momo".to_owned()),
+                ("CHAPTER001".to_owned(), "00:00:00.000".to_owned()),
+            ])
+        );
+        assert_eq!(parse_opus_tags(&tags[..tags.len() - 1]), None);
+        assert_eq!(parse_opus_tags(b"OpusHead"), None);
+    }
+}
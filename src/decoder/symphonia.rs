@@ -0,0 +1,132 @@
+use super::{ChannelOrder, DecodeError, Decoder, StreamInfo};
+use std::fs::File;
+use symphonia_core::audio::SampleBuffer;
+use symphonia_core::codecs::{self, DecoderOptions};
+use symphonia_core::errors::Error;
+use symphonia_core::formats::{FormatOptions, FormatReader, SeekMode, SeekTo};
+use symphonia_core::io::MediaSourceStream;
+
+/// Adapter for the demuxers and codecs of symphonia.
+pub struct SymphoniaDecoder {
+    reader: Box<dyn FormatReader>,
+    decoder: Box<dyn codecs::Decoder>,
+    track_id: u32,
+    info: StreamInfo,
+    duration: Option<u64>,
+    comments: Vec<(String, String)>,
+    position: u64,
+    // Frames that are decoded after a seek, but lie before the requested position.
+    skip: u64,
+}
+
+impl SymphoniaDecoder {
+    pub fn new<R, D>(f: File) -> Result<Self, DecodeError>
+    where
+        R: FormatReader + 'static,
+        D: codecs::Decoder + 'static,
+    {
+        let source = MediaSourceStream::new(Box::new(f), Default::default());
+        let options = FormatOptions {
+            // Strip encoder delay and padding.
+            enable_gapless: true,
+            ..Default::default()
+        };
+        let mut reader = R::try_new(source, &options)?;
+        let track = reader
+            .default_track()
+            .ok_or(DecodeError::UnsupportedFormat)?;
+        let params = &track.codec_params;
+        let info = StreamInfo {
+            sample_rate: params.sample_rate.ok_or(DecodeError::UnsupportedFormat)?,
+            channels: params
+                .channels
+                .ok_or(DecodeError::UnsupportedFormat)?
+                .count(),
+        };
+        let decoder = D::try_new(params, &DecoderOptions::default())?;
+        let track_id = track.id;
+        let duration = params.n_frames;
+        let comments = reader
+            .metadata()
+            .current()
+            .map(|revision| {
+                revision
+                    .tags()
+                    .iter()
+                    .map(|tag| (tag.key.clone(), tag.value.to_string()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        Ok(SymphoniaDecoder {
+            track_id,
+            duration,
+            comments,
+            reader: Box::new(reader),
+            decoder: Box::new(decoder),
+            info,
+            position: 0,
+            skip: 0,
+        })
+    }
+}
+
+impl Decoder for SymphoniaDecoder {
+    fn info(&self) -> StreamInfo {
+        self.info
+    }
+
+    // Samples are interleaved in the order of symphonia's channel flags.
+    fn channel_order(&self) -> ChannelOrder {
+        ChannelOrder::Wave
+    }
+
+    fn next_packet(&mut self) -> Result<Option<Vec<i16>>, DecodeError> {
+        let packet = loop {
+            match self.reader.next_packet() {
+                Ok(packet) if packet.track_id() == self.track_id => break packet,
+                Ok(_) => {}
+                Err(Error::IoError(ref e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    return Ok(None)
+                }
+                Err(e) => return Err(e.into()),
+            }
+        };
+        let decoded = self.decoder.decode(&packet)?;
+        let spec = *decoded.spec();
+        let mut buf = SampleBuffer::<i16>::new(decoded.capacity() as u64, spec);
+        buf.copy_interleaved_ref(decoded);
+
+        let channels = spec.channels.count();
+        let frames = (buf.samples().len() / channels) as u64;
+        let skip = self.skip.min(frames);
+        self.skip -= skip;
+        self.position += frames - skip;
+        Ok(Some(buf.samples()[skip as usize * channels..].to_vec()))
+    }
+
+    fn seek(&mut self, frame: u64) -> Result<(), DecodeError> {
+        let seeked_to = self.reader.seek(
+            SeekMode::Accurate,
+            SeekTo::TimeStamp {
+                ts: frame,
+                track_id: self.track_id,
+            },
+        )?;
+        self.decoder.reset();
+        self.skip = seeked_to.required_ts.saturating_sub(seeked_to.actual_ts);
+        self.position = seeked_to.required_ts;
+        Ok(())
+    }
+
+    fn position(&self) -> u64 {
+        self.position
+    }
+
+    fn duration(&self) -> Option<u64> {
+        self.duration
+    }
+
+    fn comments(&self) -> &[(String, String)] {
+        &self.comments
+    }
+}
@@ -0,0 +1,436 @@
+use super::{ogg_page, DecodeError, Decoder, StreamInfo};
+use lewton::inside_ogg::OggStreamReader;
+use std::collections::VecDeque;
+use std::fs::File;
+
+pub struct VorbisDecoder {
+    stream: OggStreamReader<File>,
+    position: u64,
+    duration: Option<u64>,
+    // Packets that have been decoded while seeking, but not returned yet.
+    pending: VecDeque<Vec<i16>>,
+}
+
+/// Drop the first frames of the (interleaved) packets.
+fn skip_frames(packets: &mut VecDeque<Vec<i16>>, mut frames: u64, channels: usize) {
+    while let Some(packet) = packets.front_mut() {
+        let packet_frames = (packet.len() / channels) as u64;
+        if packet_frames > frames {
+            packet.drain(..frames as usize * channels);
+            return;
+        }
+        frames -= packet_frames;
+        packets.pop_front();
+    }
+}
+
+fn frames(packets: &VecDeque<Vec<i16>>, channels: usize) -> u64 {
+    packets.iter().map(|p| (p.len() / channels) as u64).sum()
+}
+
+impl VorbisDecoder {
+    pub fn new(mut f: File) -> Result<Self, DecodeError> {
+        let duration = ogg_page::end_granule_position(&mut f)?;
+        let stream = OggStreamReader::new(f)?;
+        Ok(VorbisDecoder {
+            stream,
+            position: 0,
+            duration,
+            pending: VecDeque::new(),
+        })
+    }
+
+    fn channels(&self) -> usize {
+        self.stream.ident_hdr.audio_channels as usize
+    }
+
+    fn read_packet(&mut self) -> Result<Option<Vec<i16>>, DecodeError> {
+        match self.stream.read_dec_packet_itl() {
+            Ok(samples) => Ok(samples),
+            Err(lewton::VorbisError::BadAudio(lewton::audio::AudioReadError::AudioIsHeader)) => {
+                Ok(Some(Vec::new()))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl Decoder for VorbisDecoder {
+    fn info(&self) -> StreamInfo {
+        StreamInfo {
+            sample_rate: self.stream.ident_hdr.audio_sample_rate,
+            channels: self.channels(),
+        }
+    }
+
+    fn next_packet(&mut self) -> Result<Option<Vec<i16>>, DecodeError> {
+        if let Some(samples) = self.pending.pop_front() {
+            self.position += (samples.len() / self.channels()) as u64;
+            return Ok(Some(samples));
+        }
+        let samples = self.read_packet()?;
+        if let Some(ref samples) = samples {
+            // The granule position (at the end of the packet) is only known once the end of a
+            // page has been read. Until then we count.
+            self.position = self
+                .stream
+                .get_last_absgp()
+                .unwrap_or(self.position + (samples.len() / self.channels()) as u64);
+        }
+        Ok(samples)
+    }
+
+    fn seek(&mut self, frame: u64) -> Result<(), DecodeError> {
+        self.pending.clear();
+        let channels = self.channels();
+
+        // We can only seek to the start of a page and the first packet after that is needed to
+        // prime the decoder. Starting a (long) block earlier ensures that we do not miss the
+        // target.
+        let preroll = 1u64 << self.stream.ident_hdr.blocksize_1;
+        self.stream.seek_absgp_pg(frame.saturating_sub(preroll))?;
+
+        // Decode until the position is known and then drop everything before the target.
+        while let Some(samples) = self.read_packet()? {
+            self.pending.push_back(samples);
+            if let Some(end) = self.stream.get_last_absgp() {
+                if end > frame {
+                    let start = end.saturating_sub(frames(&self.pending, channels));
+                    skip_frames(&mut self.pending, frame.saturating_sub(start), channels);
+                    self.position = frame.max(start);
+                    return Ok(());
+                }
+                self.pending.clear();
+            }
+        }
+
+        // Beyond the end of the stream
+        self.pending.clear();
+        self.position = frame;
+        Ok(())
+    }
+
+    fn position(&self) -> u64 {
+        self.position
+    }
+
+    fn duration(&self) -> Option<u64> {
+        self.duration
+    }
+
+    fn comments(&self) -> &[(String, String)] {
+        &self.stream.comment_hdr.comment_list
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use std::io::Write;
+
+    /// Writes bits in the order of the vorbis bitpacking convention (least significant first).
+    #[derive(Default)]
+    struct BitWriter {
+        bytes: Vec<u8>,
+        bits: usize,
+    }
+
+    impl BitWriter {
+        fn write(&mut self, value: u32, bits: usize) {
+            for i in 0..bits {
+                if self.bits & 7 == 0 {
+                    self.bytes.push(0);
+                }
+                if (value >> i) & 1 == 1 {
+                    *self.bytes.last_mut().unwrap() |= 1 << (self.bits % 8);
+                }
+                self.bits += 1;
+            }
+        }
+
+        /// Huffman codewords are read starting with their most significant bit.
+        fn write_codeword(&mut self, entry: u32, length: usize) {
+            for i in (0..length).rev() {
+                self.write((entry >> i) & 1, 1);
+            }
+        }
+
+        fn write_header_start(&mut self, packet_type: u32) {
+            self.write(packet_type, 8);
+            for &b in b"vorbis" {
+                self.write(b as u32, 8);
+            }
+        }
+
+        /// A codebook with equally long codewords for all entries. Scalar values of the codebook
+        /// are the entry numbers, vector values (if any) are the entry numbers offset by min.
+        fn write_codebook(&mut self, entries: u32, length: usize, min: Option<i32>) {
+            self.write(0x564342, 24);
+            self.write(1, 16); // dimensions
+            self.write(entries, 24);
+            self.write(0, 1); // not ordered
+            self.write(0, 1); // not sparse
+            for _ in 0..entries {
+                self.write(length as u32 - 1, 5);
+            }
+            match min {
+                None => self.write(0, 4),
+                Some(min) => {
+                    self.write(1, 4); // lookup type
+                    let float = |v: i32| (788 << 21) | v.unsigned_abs() | ((v < 0) as u32) << 31;
+                    self.write(float(min), 32);
+                    self.write(float(1), 32); // delta
+                    self.write(length as u32 - 1, 4); // value bits
+                    self.write(0, 1); // sequence_p
+                    for i in 0..entries {
+                        self.write(i, length);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Size (as a power of two) of all blocks of the generated streams
+    const BLOCKSIZE: u32 = 8;
+    /// Number of coefficients of a partition of the residue
+    const PARTITION_SIZE: u32 = 32;
+
+    fn setup_header() -> Vec<u8> {
+        let mut w = BitWriter::default();
+        w.write_header_start(5);
+        w.write(2, 8); // three codebooks:
+        w.write_codebook(256, 8, None); // floor values
+        w.write_codebook(16, 4, Some(-8)); // residue values
+        w.write_codebook(2, 1, None); // residue classes
+        w.write(0, 6); // one time domain transform
+        w.write(0, 16);
+
+        // A floor with a single partition, which adds the point in the middle.
+        w.write(0, 6); // one floor
+        w.write(1, 16); // type 1
+        w.write(1, 5); // partitions
+        w.write(0, 4); // class of the partition
+        w.write(0, 3); // class dimensions - 1
+        w.write(0, 2); // no subclasses
+        w.write(1, 8); // subclass book + 1
+        w.write(0, 2); // multiplier - 1
+        w.write(BLOCKSIZE - 2, 4); // range bits
+        w.write(1 << (BLOCKSIZE - 3), BLOCKSIZE as usize - 2); // x of the middle
+
+        w.write(0, 6); // one residue
+        w.write(1, 16); // type 1
+        w.write(0, 24); // begin
+        w.write(1 << (BLOCKSIZE - 1), 24); // end
+        w.write(PARTITION_SIZE - 1, 24);
+        w.write(1, 6); // two classes
+        w.write(2, 8); // class book
+        w.write(0, 4); // class 0 is empty
+        w.write(1, 4); // class 1 has a book in the first pass
+        w.write(1, 8); // the residue value book
+
+        w.write(0, 6); // one mapping
+        w.write(0, 16); // type 0
+        w.write(0, 1); // one submap
+        w.write(0, 1); // no coupling
+        w.write(0, 2); // reserved
+        w.write(0, 8); // unused time config
+        w.write(0, 8); // floor
+        w.write(0, 8); // residue
+
+        w.write(0, 6); // one mode
+        w.write(0, 1); // short blocks
+        w.write(0, 16); // window type
+        w.write(0, 16); // transform type
+        w.write(0, 8); // mapping
+        w.write(1, 1); // framing
+        w.bytes
+    }
+
+    /// An audio packet with a flat floor and random residue values for every channel.
+    fn audio_packet(channels: u8, random: &mut impl FnMut() -> u32) -> Vec<u8> {
+        let mut w = BitWriter::default();
+        w.write(0, 1); // audio
+        for _ in 0..channels {
+            w.write(1, 1); // nonzero
+            let level = 150 + random() % 20;
+            w.write(level, 8);
+            w.write(level, 8);
+            w.write_codeword(0, 8); // the middle is as predicted
+        }
+        for _ in 0..(1 << (BLOCKSIZE - 1)) / PARTITION_SIZE {
+            for _ in 0..channels {
+                w.write_codeword(1, 1); // class
+            }
+            for _ in 0..channels {
+                for _ in 0..PARTITION_SIZE {
+                    w.write_codeword(random() % 16, 4);
+                }
+            }
+        }
+        w.bytes
+    }
+
+    fn ogg_crc(data: &[u8]) -> u32 {
+        let mut crc = 0u32;
+        for &b in data {
+            crc ^= (b as u32) << 24;
+            for _ in 0..8 {
+                crc = if crc & 0x8000_0000 != 0 {
+                    (crc << 1) ^ 0x04c1_1db7
+                } else {
+                    crc << 1
+                };
+            }
+        }
+        crc
+    }
+
+    fn ogg_page(packets: &[Vec<u8>], header_type: u8, granule: u64, sequence: u32) -> Vec<u8> {
+        let mut segments = Vec::new();
+        for p in packets {
+            segments.resize(segments.len() + p.len() / 255, 255);
+            segments.push((p.len() % 255) as u8);
+        }
+        let mut page = b"OggS\0".to_vec();
+        page.push(header_type);
+        page.extend_from_slice(&granule.to_le_bytes());
+        page.extend_from_slice(&1u32.to_le_bytes()); // serial
+        page.extend_from_slice(&sequence.to_le_bytes());
+        page.extend_from_slice(&[0; 4]); // crc
+        page.push(segments.len() as u8);
+        page.extend(segments);
+        for p in packets {
+            page.extend_from_slice(p);
+        }
+        let crc = ogg_crc(&page);
+        page[22..26].copy_from_slice(&crc.to_le_bytes());
+        page
+    }
+
+    /// Build an ogg vorbis file with the given number of audio packets, which contain noise. All
+    /// packets but the first one produce 128 frames.
+    pub fn vorbis_file(channels: u8, packets: usize) -> Vec<u8> {
+        let mut ident = BitWriter::default();
+        ident.write_header_start(1);
+        ident.write(0, 32); // version
+        ident.write(channels as u32, 8);
+        ident.write(44100, 32);
+        for _ in 0..3 {
+            ident.write(0, 32); // bitrates
+        }
+        ident.write(BLOCKSIZE, 4);
+        ident.write(BLOCKSIZE, 4);
+        ident.write(1, 1); // framing
+
+        let mut comment = BitWriter::default();
+        comment.write_header_start(3);
+        comment.write(0, 32); // no vendor
+        comment.write(0, 32); // no comments
+        comment.write(1, 1); // framing
+
+        let mut f = ogg_page(&[ident.bytes], 0x02, 0, 0);
+        f.extend(ogg_page(&[comment.bytes, setup_header()], 0, 0, 1));
+
+        let mut state = 0x1234_5678u32;
+        let mut random = move || {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            state >> 16
+        };
+        let frames_per_packet = 1 << (BLOCKSIZE - 1);
+        let audio = (0..packets)
+            .map(|_| audio_packet(channels, &mut random))
+            .collect::<Vec<_>>();
+        for (i, page) in audio.chunks(8).enumerate() {
+            let end = (i * 8 + page.len() - 1) as u64 * frames_per_packet;
+            let last = (i + 1) * 8 >= packets;
+            f.extend(ogg_page(
+                page,
+                if last { 0x04 } else { 0 },
+                end,
+                i as u32 + 2,
+            ));
+        }
+        f
+    }
+
+    /// Write the file to a temporary path with the given name.
+    fn temp_file(name: &str, data: &[u8]) -> std::path::PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("kassette_{}_{}.ogg", name, std::process::id()));
+        std::fs::File::create(&path)
+            .and_then(|mut f| f.write_all(data))
+            .unwrap();
+        path
+    }
+
+    fn decode_all(d: &mut VorbisDecoder) -> Vec<i16> {
+        let mut samples = Vec::new();
+        while let Some(packet) = d.next_packet().unwrap() {
+            samples.extend(packet);
+        }
+        samples
+    }
+
+    #[test]
+    fn test_seek() {
+        for &channels in &[1u8, 2] {
+            let path = temp_file(&format!("seek_{}", channels), &vorbis_file(channels, 100));
+            let open = || VorbisDecoder::new(File::open(&path).unwrap()).unwrap();
+            let channels = channels as usize;
+
+            let mut d = open();
+            let reference = decode_all(&mut d);
+            let frames = (reference.len() / channels) as u64;
+            assert_eq!(frames, 99 * 128);
+            assert_eq!(d.duration(), Some(frames));
+            assert_eq!(d.position(), frames);
+            assert!(reference.iter().any(|s| s.abs() > 1000));
+            assert!(reference.iter().all(|s| s.abs() < 30000));
+            if channels == 2 {
+                assert!(reference.chunks(2).any(|f| f[0] != f[1]));
+            }
+
+            // Targets within the first page, at page boundaries and in the middle of packets
+            for &frame in &[0, 5, 127, 8 * 128, 8 * 128 + 1, 3000, frames - 1] {
+                d.seek(frame).unwrap();
+                assert_eq!(d.position(), frame);
+                let packet = d.next_packet().unwrap().unwrap();
+                assert!(!packet.is_empty());
+                let start = frame as usize * channels;
+                assert_eq!(packet[..], reference[start..start + packet.len()]);
+                assert_eq!(d.position(), frame + (packet.len() / channels) as u64);
+            }
+
+            d.seek(frames + 100).unwrap();
+            assert_eq!(d.position(), frames + 100);
+            assert_eq!(d.next_packet().unwrap(), None);
+
+            // The position of sequential decoding agrees with the one after seeking.
+            let mut d = open();
+            d.seek(1000).unwrap();
+            assert_eq!(decode_all(&mut d)[..], reference[1000 * channels..]);
+            assert_eq!(d.position(), frames);
+
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_skip_frames() {
+        // mono
+        let mut packets = VecDeque::from(vec![vec![0, 1, 2], vec![], vec![3, 4, 5, 6]]);
+        assert_eq!(frames(&packets, 1), 7);
+        skip_frames(&mut packets, 4, 1);
+        assert_eq!(packets, VecDeque::from(vec![vec![4, 5, 6]]));
+
+        // stereo
+        let mut packets = VecDeque::from(vec![vec![0, 0, 1, 1], vec![2, 2, 3, 3, 4, 4]]);
+        assert_eq!(frames(&packets, 2), 5);
+        skip_frames(&mut packets, 2, 2);
+        assert_eq!(packets, VecDeque::from(vec![vec![2, 2, 3, 3, 4, 4]]));
+        skip_frames(&mut packets, 0, 2);
+        assert_eq!(frames(&packets, 2), 3);
+        skip_frames(&mut packets, 5, 2);
+        assert!(packets.is_empty());
+    }
+}
@@ -0,0 +1,265 @@
+use super::{ChannelOrder, DecodeError, Decoder, StreamInfo};
+use std::io::{Read, Seek, SeekFrom};
+
+const FORMAT_PCM: u16 = 1;
+const FORMAT_EXTENSIBLE: u16 = 0xfffe;
+/// Size of the largest fmt chunk that we accept. WAVE_FORMAT_EXTENSIBLE needs 40 bytes.
+const MAX_FMT_LEN: u64 = 64;
+
+/// Number of frames that are decoded at once.
+const PACKET_FRAMES: u64 = 4096;
+
+/// Decoder for uncompressed integer PCM data in a RIFF/WAVE container.
+pub struct WavDecoder<R> {
+    reader: R,
+    info: StreamInfo,
+    bytes_per_sample: usize,
+    data_start: u64,
+    frames: u64,
+    position: u64,
+}
+
+fn read_u16(b: &[u8]) -> u16 {
+    u16::from_le_bytes([b[0], b[1]])
+}
+
+fn read_u32(b: &[u8]) -> u32 {
+    u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+}
+
+fn invalid(msg: &str) -> DecodeError {
+    DecodeError::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, msg))
+}
+
+impl<R: Read + Seek> WavDecoder<R> {
+    pub fn new(mut reader: R) -> Result<Self, DecodeError> {
+        let mut header = [0u8; 12];
+        reader.read_exact(&mut header)?;
+        if &header[0..4] != b"RIFF" || &header[8..12] != b"WAVE" {
+            return Err(DecodeError::UnsupportedFormat);
+        }
+
+        let mut fmt = None;
+        loop {
+            let mut chunk_header = [0u8; 8];
+            reader.read_exact(&mut chunk_header)?;
+            let chunk_len = read_u32(&chunk_header[4..]) as u64;
+            match &chunk_header[0..4] {
+                b"fmt " => {
+                    // The length is not trusted before the allocation.
+                    if chunk_len > MAX_FMT_LEN {
+                        return Err(invalid("fmt chunk too long"));
+                    }
+                    let mut chunk = vec![0u8; chunk_len as usize];
+                    reader.read_exact(&mut chunk)?;
+                    if chunk_len % 2 == 1 {
+                        reader.seek(SeekFrom::Current(1))?;
+                    }
+                    fmt = Some(chunk);
+                }
+                b"data" => {
+                    let fmt = fmt.ok_or_else(|| invalid("data chunk before fmt chunk"))?;
+                    return Self::with_format(reader, &fmt, chunk_len);
+                }
+                // Chunks are padded to an even number of bytes.
+                _ => {
+                    reader.seek(SeekFrom::Current((chunk_len + chunk_len % 2) as i64))?;
+                }
+            }
+        }
+    }
+
+    fn with_format(mut reader: R, fmt: &[u8], data_len: u64) -> Result<Self, DecodeError> {
+        if fmt.len() < 16 {
+            return Err(invalid("fmt chunk too short"));
+        }
+        let format = match read_u16(&fmt[0..]) {
+            // The actual format is stored in the first two bytes of the sub format guid.
+            FORMAT_EXTENSIBLE if fmt.len() >= 26 => read_u16(&fmt[24..]),
+            format => format,
+        };
+        let channels = read_u16(&fmt[2..]) as usize;
+        let sample_rate = read_u32(&fmt[4..]);
+        let block_align = read_u16(&fmt[12..]) as usize;
+        let bits_per_sample = read_u16(&fmt[14..]);
+        let bytes_per_sample = (bits_per_sample as usize).div_ceil(8);
+
+        if format != FORMAT_PCM || !(1..=4).contains(&bytes_per_sample) {
+            return Err(DecodeError::UnsupportedFormat);
+        }
+        if channels == 0 || sample_rate == 0 || block_align != channels * bytes_per_sample {
+            return Err(invalid("inconsistent fmt chunk"));
+        }
+
+        let data_start = reader.stream_position()?;
+        let file_len = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(data_start))?;
+        // Streaming writers may not know the length of the data in advance.
+        let data_len = data_len.min(file_len - data_start);
+
+        Ok(WavDecoder {
+            reader,
+            info: StreamInfo {
+                sample_rate,
+                channels,
+            },
+            bytes_per_sample,
+            data_start,
+            frames: data_len / block_align as u64,
+            position: 0,
+        })
+    }
+}
+
+impl<R: Read + Seek + Send> Decoder for WavDecoder<R> {
+    fn info(&self) -> StreamInfo {
+        self.info
+    }
+
+    fn channel_order(&self) -> ChannelOrder {
+        ChannelOrder::Wave
+    }
+
+    fn next_packet(&mut self) -> Result<Option<Vec<i16>>, DecodeError> {
+        let frames = PACKET_FRAMES.min(self.frames - self.position);
+        if frames == 0 {
+            return Ok(None);
+        }
+        let mut buf = vec![0u8; frames as usize * self.info.channels * self.bytes_per_sample];
+        self.reader.read_exact(&mut buf)?;
+        self.position += frames;
+
+        // Only the most significant 16 bits are used.
+        let samples = buf
+            .chunks(self.bytes_per_sample)
+            .map(|s| match *s {
+                [b] => ((b as i16) - 128) << 8,
+                [.., b1, b0] => i16::from_le_bytes([b1, b0]),
+                [] => unreachable!(),
+            })
+            .collect();
+        Ok(Some(samples))
+    }
+
+    fn seek(&mut self, frame: u64) -> Result<(), DecodeError> {
+        let frame = frame.min(self.frames);
+        let block_align = (self.info.channels * self.bytes_per_sample) as u64;
+        self.reader
+            .seek(SeekFrom::Start(self.data_start + frame * block_align))?;
+        self.position = frame;
+        Ok(())
+    }
+
+    fn position(&self) -> u64 {
+        self.position
+    }
+
+    fn duration(&self) -> Option<u64> {
+        Some(self.frames)
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Build a wav file with the given samples, which are written with the given number of bytes.
+    pub fn wav_file(channels: u16, bytes_per_sample: u16, samples: &[i32]) -> Vec<u8> {
+        let data_len = samples.len() as u32 * bytes_per_sample as u32;
+        let mut f = Vec::new();
+        f.extend_from_slice(b"RIFF");
+        f.extend_from_slice(&(4 + 8 + 16 + 8 + 5 + 1 + 8 + data_len).to_le_bytes());
+        f.extend_from_slice(b"WAVE");
+        f.extend_from_slice(b"fmt ");
+        f.extend_from_slice(&16u32.to_le_bytes());
+        f.extend_from_slice(&FORMAT_PCM.to_le_bytes());
+        f.extend_from_slice(&channels.to_le_bytes());
+        f.extend_from_slice(&44100u32.to_le_bytes());
+        let block_align = channels * bytes_per_sample;
+        f.extend_from_slice(&(44100 * block_align as u32).to_le_bytes());
+        f.extend_from_slice(&block_align.to_le_bytes());
+        f.extend_from_slice(&(bytes_per_sample * 8).to_le_bytes());
+        // Unknown chunks with odd length are skipped including their padding.
+        f.extend_from_slice(b"LIST");
+        f.extend_from_slice(&5u32.to_le_bytes());
+        f.extend_from_slice(b"info\0\0");
+        f.extend_from_slice(b"data");
+        f.extend_from_slice(&data_len.to_le_bytes());
+        for s in samples {
+            f.extend_from_slice(&s.to_le_bytes()[..bytes_per_sample as usize]);
+        }
+        f
+    }
+
+    #[test]
+    fn test_sample_formats() {
+        let samples = [0x00, 0x7f, 0x80, 0xff];
+        let mut d = WavDecoder::new(Cursor::new(wav_file(1, 1, &samples))).unwrap();
+        assert_eq!(
+            d.next_packet().unwrap(),
+            Some(vec![-0x8000, -0x0100, 0x0000, 0x7f00])
+        );
+
+        let samples = [0x1234, -0x1234, i16::MAX as i32, i16::MIN as i32];
+        let mut d = WavDecoder::new(Cursor::new(wav_file(2, 2, &samples))).unwrap();
+        assert_eq!(
+            d.info(),
+            StreamInfo {
+                sample_rate: 44100,
+                channels: 2
+            }
+        );
+        assert_eq!(d.duration(), Some(2));
+        assert_eq!(
+            d.next_packet().unwrap(),
+            Some(vec![0x1234, -0x1234, i16::MAX, i16::MIN])
+        );
+        assert_eq!(d.next_packet().unwrap(), None);
+
+        let samples = [0x123456, -0x123456, 0x7fffff, -0x800000];
+        let mut d = WavDecoder::new(Cursor::new(wav_file(2, 3, &samples))).unwrap();
+        assert_eq!(
+            d.next_packet().unwrap(),
+            Some(vec![0x1234, -0x1235, i16::MAX, i16::MIN])
+        );
+    }
+
+    #[test]
+    fn test_seek() {
+        let samples = (0..3 * PACKET_FRAMES as i32).collect::<Vec<_>>();
+        let mut d = WavDecoder::new(Cursor::new(wav_file(1, 2, &samples))).unwrap();
+        assert_eq!(d.duration(), Some(3 * PACKET_FRAMES));
+
+        d.seek(PACKET_FRAMES + 10).unwrap();
+        assert_eq!(d.position(), PACKET_FRAMES + 10);
+        let packet = d.next_packet().unwrap().unwrap();
+        assert_eq!(packet[0], PACKET_FRAMES as i16 + 10);
+        assert_eq!(d.position(), 2 * PACKET_FRAMES + 10);
+        assert_eq!(d.next_packet().unwrap().unwrap().len(), 4086);
+        assert_eq!(d.next_packet().unwrap(), None);
+
+        d.seek(5 * PACKET_FRAMES).unwrap();
+        assert_eq!(d.next_packet().unwrap(), None);
+        d.seek(0).unwrap();
+        assert_eq!(d.next_packet().unwrap().unwrap()[1], 1);
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let mut f = wav_file(1, 2, &[0, 1]);
+        f[20] = 3; // IEEE float
+        assert!(matches!(
+            WavDecoder::new(Cursor::new(f)),
+            Err(DecodeError::UnsupportedFormat)
+        ));
+        assert!(WavDecoder::new(Cursor::new(b"RIFF\0\0\0\0WAVE".to_vec())).is_err());
+
+        let mut f = wav_file(1, 2, &[0, 1]);
+        f[16..20].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(
+            WavDecoder::new(Cursor::new(f)),
+            Err(DecodeError::Io(_))
+        ));
+    }
+}
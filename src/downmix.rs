@@ -0,0 +1,157 @@
+use crate::decoder::ChannelOrder;
+use std::f32::consts::FRAC_1_SQRT_2;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Speaker {
+    Mono,
+    Left,
+    Right,
+    Center,
+    SurroundLeft,
+    SurroundRight,
+    RearCenter,
+    Lfe,
+}
+
+impl Speaker {
+    /// Contribution to the left and right output channel (before normalization)
+    fn gains(self) -> [f32; 2] {
+        // Channels that are not directly at the front are mixed in at -3 dB.
+        let g = FRAC_1_SQRT_2;
+        match self {
+            Speaker::Mono => [1.0, 1.0],
+            Speaker::Left => [1.0, 0.0],
+            Speaker::Right => [0.0, 1.0],
+            Speaker::Center => [g, g],
+            Speaker::SurroundLeft => [g, 0.0],
+            Speaker::SurroundRight => [0.0, g],
+            Speaker::RearCenter => [g * g, g * g],
+            // Small speakers cannot play it anyway.
+            Speaker::Lfe => [0.0, 0.0],
+        }
+    }
+}
+
+/// The speakers of the channels of a frame. Side and rear channels are treated alike.
+fn layout(channels: usize, order: ChannelOrder) -> Option<&'static [Speaker]> {
+    use Speaker::*;
+    Some(match (order, channels) {
+        (_, 1) => &[Mono],
+        (_, 2) => &[Left, Right],
+        (ChannelOrder::Vorbis, 3) => &[Left, Center, Right],
+        (ChannelOrder::Vorbis, 4) => &[Left, Right, SurroundLeft, SurroundRight],
+        (ChannelOrder::Vorbis, 5) => &[Left, Center, Right, SurroundLeft, SurroundRight],
+        (ChannelOrder::Vorbis, 6) => &[Left, Center, Right, SurroundLeft, SurroundRight, Lfe],
+        (ChannelOrder::Vorbis, 7) => &[
+            Left,
+            Center,
+            Right,
+            SurroundLeft,
+            SurroundRight,
+            RearCenter,
+            Lfe,
+        ],
+        (ChannelOrder::Vorbis, 8) => &[
+            Left,
+            Center,
+            Right,
+            SurroundLeft,
+            SurroundRight,
+            SurroundLeft,
+            SurroundRight,
+            Lfe,
+        ],
+        (ChannelOrder::Wave, 3) => &[Left, Right, Center],
+        (ChannelOrder::Wave, 4) => &[Left, Right, SurroundLeft, SurroundRight],
+        (ChannelOrder::Wave, 5) => &[Left, Right, Center, SurroundLeft, SurroundRight],
+        (ChannelOrder::Wave, 6) => &[Left, Right, Center, Lfe, SurroundLeft, SurroundRight],
+        (ChannelOrder::Wave, 7) => &[
+            Left,
+            Right,
+            Center,
+            Lfe,
+            RearCenter,
+            SurroundLeft,
+            SurroundRight,
+        ],
+        (ChannelOrder::Wave, 8) => &[
+            Left,
+            Right,
+            Center,
+            Lfe,
+            SurroundLeft,
+            SurroundRight,
+            SurroundLeft,
+            SurroundRight,
+        ],
+        _ => return None,
+    })
+}
+
+/// Mixes the channels of a frame down to stereo.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Downmix {
+    /// Gains of every input channel for the left and right output channel
+    matrix: Vec<[f32; 2]>,
+}
+
+impl Downmix {
+    /// The downmix for a stream with the given channels. Returns None for unknown layouts.
+    pub fn new(channels: usize, order: ChannelOrder) -> Option<Self> {
+        let mut matrix = layout(channels, order)?
+            .iter()
+            .map(|s| s.gains())
+            .collect::<Vec<_>>();
+        // Scale down so that the output cannot clip.
+        let sum = |i: usize| matrix.iter().map(|g| g[i]).sum::<f32>();
+        let max = sum(0).max(sum(1));
+        for g in matrix.iter_mut() {
+            g[0] /= max;
+            g[1] /= max;
+        }
+        Some(Downmix { matrix })
+    }
+
+    pub fn channels(&self) -> usize {
+        self.matrix.len()
+    }
+
+    /// The stereo frame for a frame of the input.
+    pub fn frame(&self, samples: &[i16]) -> [f32; 2] {
+        let mut frame = [0.0; 2];
+        for (g, &s) in self.matrix.iter().zip(samples) {
+            frame[0] += g[0] * s as f32;
+            frame[1] += g[1] * s as f32;
+        }
+        frame
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_downmix() {
+        let mono = Downmix::new(1, ChannelOrder::Vorbis).unwrap();
+        assert_eq!(mono.frame(&[100]), [100.0, 100.0]);
+        let stereo = Downmix::new(2, ChannelOrder::Wave).unwrap();
+        assert_eq!(stereo.frame(&[100, -100]), [100.0, -100.0]);
+
+        // 5.1: The lfe is dropped and a full scale signal on all other channels does not clip.
+        let vorbis = Downmix::new(6, ChannelOrder::Vorbis).unwrap();
+        let [l, r] = vorbis.frame(&[i16::MAX, i16::MAX, i16::MAX, i16::MAX, i16::MAX, 0]);
+        assert!((l - i16::MAX as f32).abs() < 0.1);
+        assert!((r - i16::MAX as f32).abs() < 0.1);
+        assert_eq!(vorbis.frame(&[0, 0, 0, 0, 0, 1000]), [0.0, 0.0]);
+
+        // The same layout in both channel orders
+        let wave = Downmix::new(6, ChannelOrder::Wave).unwrap();
+        let [l, r] = vorbis.frame(&[1000, 2000, 0, 0, 500, 0]);
+        assert_eq!(wave.frame(&[1000, 0, 2000, 0, 0, 500]), [l, r]);
+        assert!(l > r);
+
+        assert_eq!(Downmix::new(0, ChannelOrder::Vorbis), None);
+        assert_eq!(Downmix::new(9, ChannelOrder::Vorbis), None);
+    }
+}
@@ -0,0 +1,63 @@
+use std::f32::consts::PI;
+
+/// Short sounds that give feedback about actions that do not start any media playback.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Earcon {
+    /// Rising two-tone chime.
+    Confirm,
+    /// Falling two-tone chime.
+    Cancel,
+    /// Low buzz.
+    Error,
+}
+
+impl Earcon {
+    /// (frequency in Hz, duration in ms) of the consecutive tones.
+    fn tones(self) -> &'static [(f32, u32)] {
+        match self {
+            Earcon::Confirm => &[(660.0, 120), (880.0, 180)],
+            Earcon::Cancel => &[(880.0, 120), (660.0, 180)],
+            Earcon::Error => &[(220.0, 150), (0.0, 80), (220.0, 250)],
+        }
+    }
+
+    /// Interleaved stereo samples of the earcon at full volume.
+    pub fn render(self, sample_rate: u64) -> Vec<i16> {
+        const AMPLITUDE: f32 = 0.5 * i16::MAX as f32;
+        const RAMP_MS: u32 = 10;
+
+        let mut samples = Vec::new();
+        for &(freq, millis) in self.tones() {
+            let len = (sample_rate * millis as u64 / 1000) as usize;
+            // Short linear ramps at both ends of each tone avoid audible clicks.
+            let ramp = (sample_rate * RAMP_MS as u64 / 1000).max(1) as usize;
+            for i in 0..len {
+                let envelope = (i.min(len - 1 - i) as f32 / ramp as f32).min(1.0);
+                let t = i as f32 / sample_rate as f32;
+                let s = (AMPLITUDE * envelope * (2.0 * PI * freq * t).sin()) as i16;
+                samples.push(s);
+                samples.push(s);
+            }
+        }
+        samples
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        for &earcon in &[Earcon::Confirm, Earcon::Cancel, Earcon::Error] {
+            let samples = earcon.render(44100);
+            let millis = earcon.tones().iter().map(|t| t.1 as usize).sum::<usize>();
+            assert_eq!(samples.len(), 2 * 44100 * millis / 1000);
+            assert!(samples.chunks(2).all(|c| c[0] == c[1]));
+            assert_eq!(samples[0], 0);
+            assert!(samples[samples.len() - 1].abs() < 100);
+            assert!(samples.iter().any(|s| s.abs() > i16::MAX / 4));
+        }
+        assert_ne!(Earcon::Confirm.render(44100), Earcon::Cancel.render(44100));
+    }
+}
@@ -0,0 +1,200 @@
+use std::time::Duration;
+
+/// Shape of the gain over the course of a fade.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FadeCurve {
+    Linear,
+    /// Keeps the sum of the power of both directions constant, like a crossfade.
+    EqualPower,
+    /// Evenly spaced in dB, which sounds most even to the ear.
+    Logarithmic,
+}
+
+/// Range (in dB) of the logarithmic curve. Below that, it drops to silence.
+const LOGARITHMIC_RANGE: f32 = 60.0;
+
+impl FadeCurve {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "linear" => Some(FadeCurve::Linear),
+            "equal-power" => Some(FadeCurve::EqualPower),
+            "logarithmic" => Some(FadeCurve::Logarithmic),
+            _ => None,
+        }
+    }
+
+    /// The gain at the given progress (between 0 and 1) of a fade in.
+    fn gain(self, progress: f32) -> f32 {
+        let p = progress.clamp(0.0, 1.0);
+        match self {
+            FadeCurve::Linear => p,
+            FadeCurve::EqualPower => (p * std::f32::consts::FRAC_PI_2).sin(),
+            FadeCurve::Logarithmic if p == 0.0 => 0.0,
+            FadeCurve::Logarithmic => 10f32.powf((p - 1.0) * LOGARITHMIC_RANGE / 20.0),
+        }
+    }
+}
+
+fn frames(time: Duration, sample_rate: u64) -> u64 {
+    time.as_micros() as u64 * sample_rate / 1_000_000
+}
+
+/// A fade in or out over an exact number of output frames.
+#[derive(Clone, Debug)]
+pub struct Fade {
+    curve: FadeCurve,
+    frames: u64,
+    /// Number of frames of the fade that have been played
+    pos: u64,
+    fade_in: bool,
+}
+
+impl Fade {
+    pub fn fade_in(curve: FadeCurve, time: Duration, sample_rate: u64) -> Self {
+        Fade {
+            curve,
+            frames: frames(time, sample_rate),
+            pos: 0,
+            fade_in: true,
+        }
+    }
+
+    pub fn fade_out(curve: FadeCurve, time: Duration, sample_rate: u64) -> Self {
+        Fade {
+            fade_in: false,
+            ..Self::fade_in(curve, time, sample_rate)
+        }
+    }
+
+    /// Fade in the other direction, starting at the current gain.
+    pub fn reverse(self) -> Self {
+        Fade {
+            pos: self.frames - self.pos,
+            fade_in: !self.fade_in,
+            ..self
+        }
+    }
+
+    pub fn finished(&self) -> bool {
+        self.pos >= self.frames
+    }
+
+    /// The gain for the next frame.
+    pub fn next_gain(&mut self) -> f32 {
+        let progress = if self.frames == 0 {
+            1.0
+        } else {
+            self.pos as f32 / self.frames as f32
+        };
+        self.pos = (self.pos + 1).min(self.frames);
+        self.curve.gain(if self.fade_in {
+            progress
+        } else {
+            1.0 - progress
+        })
+    }
+}
+
+/// A gain that moves to a new value gradually instead of jumping, which would be audible as a
+/// click.
+#[derive(Clone, Debug)]
+pub struct SmoothGain {
+    current: f32,
+    target: f32,
+    /// Change per frame
+    step: f32,
+}
+
+impl SmoothGain {
+    pub fn new(gain: f32) -> Self {
+        SmoothGain {
+            current: gain,
+            target: gain,
+            step: 0.0,
+        }
+    }
+
+    /// Move to the new gain within the given time.
+    pub fn set_target(&mut self, target: f32, time: Duration, sample_rate: u64) {
+        if target != self.target {
+            self.target = target;
+            self.step = (target - self.current).abs() / frames(time, sample_rate).max(1) as f32;
+        }
+    }
+
+    /// The gain for the next frame.
+    pub fn next_gain(&mut self) -> f32 {
+        if self.current < self.target {
+            self.current = (self.current + self.step).min(self.target);
+        } else {
+            self.current = (self.current - self.step).max(self.target);
+        }
+        self.current
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn assert_close(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-6, "{:?} {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn test_curves() {
+        for &curve in &[
+            FadeCurve::Linear,
+            FadeCurve::EqualPower,
+            FadeCurve::Logarithmic,
+        ] {
+            assert_eq!(curve.gain(0.0), 0.0);
+            assert_eq!(curve.gain(1.0), 1.0);
+            assert!(curve.gain(0.25) < curve.gain(0.5));
+        }
+        assert_eq!(FadeCurve::Linear.gain(0.5), 0.5);
+        let g = FadeCurve::EqualPower.gain(0.5);
+        assert!((2.0 * g * g - 1.0).abs() < 1e-6);
+        assert!((FadeCurve::Logarithmic.gain(0.5) - 10f32.powf(-1.5)).abs() < 1e-6);
+        assert_eq!(FadeCurve::parse("equal-power"), Some(FadeCurve::EqualPower));
+        assert_eq!(FadeCurve::parse("exponential"), None);
+    }
+
+    #[test]
+    fn test_fade() {
+        // 10 frames
+        let mut fade = Fade::fade_in(FadeCurve::Linear, Duration::from_millis(10), 1000);
+        let gains = (0..5).map(|_| fade.next_gain()).collect::<Vec<_>>();
+        assert_close(&gains, &[0.0, 0.1, 0.2, 0.3, 0.4]);
+        assert!(!fade.finished());
+
+        // Fading out again starts at the current gain.
+        let mut fade = fade.reverse();
+        assert_close(&[fade.next_gain(), fade.next_gain()], &[0.5, 0.4]);
+        for _ in 0..2 {
+            fade.next_gain();
+        }
+        assert!(!fade.finished());
+        assert_close(&[fade.next_gain()], &[0.1]);
+        assert!(fade.finished());
+        assert_eq!(fade.next_gain(), 0.0);
+
+        let mut fade = Fade::fade_out(FadeCurve::EqualPower, Duration::from_millis(0), 44100);
+        assert!(fade.finished());
+        assert_eq!(fade.next_gain(), 0.0);
+    }
+
+    #[test]
+    fn test_smooth_gain() {
+        let mut gain = SmoothGain::new(1.0);
+        assert_eq!(gain.next_gain(), 1.0);
+        gain.set_target(0.5, Duration::from_millis(5), 1000);
+        let gains = (0..6).map(|_| gain.next_gain()).collect::<Vec<_>>();
+        assert_close(&gains, &[0.9, 0.8, 0.7, 0.6, 0.5, 0.5]);
+        gain.set_target(1.0, Duration::from_millis(0), 1000);
+        assert_eq!(gain.next_gain(), 1.0);
+    }
+}
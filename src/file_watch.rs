@@ -0,0 +1,111 @@
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Notifies about changes of a single file. We use inotify if possible and fall back to polling
+/// the modification time of the file otherwise.
+pub struct FileWatcher {
+    path: PathBuf,
+    method: Method,
+}
+
+enum Method {
+    Inotify(Inotify),
+    Poll(Option<SystemTime>),
+}
+
+fn modification_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn init_inotify(path: &Path) -> nix::Result<Inotify> {
+    let inotify = Inotify::init(InitFlags::IN_CLOEXEC)?;
+
+    // Editors often write a new file and move it over the old one, so we have to watch the
+    // directory instead of the file itself.
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    inotify.add_watch(
+        dir,
+        AddWatchFlags::IN_CLOSE_WRITE | AddWatchFlags::IN_MOVED_TO,
+    )?;
+    Ok(inotify)
+}
+
+impl FileWatcher {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref().to_owned();
+        let method = match init_inotify(&path) {
+            Ok(inotify) => Method::Inotify(inotify),
+            Err(e) => {
+                log!("Cannot use inotify, polling {:?} instead: {}", path, e);
+                Method::Poll(modification_time(&path))
+            }
+        };
+        FileWatcher { path, method }
+    }
+
+    /// Block until the file has been changed.
+    pub fn wait_for_change(&mut self) {
+        loop {
+            let result = match self.method {
+                Method::Inotify(ref inotify) => inotify.read_events(),
+                Method::Poll(ref mut last_modified) => {
+                    std::thread::sleep(crate::config::FILE_WATCH_POLL_INTERVAL);
+                    let modified = modification_time(&self.path);
+                    if modified != *last_modified {
+                        *last_modified = modified;
+                        return;
+                    }
+                    continue;
+                }
+            };
+            match result {
+                Ok(events) => {
+                    let file_name = self.path.file_name();
+                    if events.iter().any(|e| e.name.as_deref() == file_name) {
+                        return;
+                    }
+                }
+                Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => {}
+                Err(e) => {
+                    log!("Reading inotify events failed, polling instead: {}", e);
+                    self.method = Method::Poll(modification_time(&self.path));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_inotify_replace() {
+        let dir = std::env::temp_dir().join(format!("kassette_watch_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("watched.txt");
+        std::fs::write(&path, b"old").unwrap();
+
+        let mut watcher = FileWatcher::new(&path);
+        assert!(matches!(watcher.method, Method::Inotify(_)));
+
+        let writer = {
+            let dir = dir.clone();
+            let path = path.clone();
+            std::thread::spawn(move || {
+                // Changes to other files in the directory are not reported...
+                std::fs::write(dir.join("other.txt"), b"other").unwrap();
+                // ...but replacing the watched file is.
+                std::fs::write(dir.join("watched.txt.tmp"), b"new").unwrap();
+                std::fs::rename(dir.join("watched.txt.tmp"), &path).unwrap();
+            })
+        };
+        watcher.wait_for_change();
+        writer.join().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,371 @@
+use crate::loudness::Biquad;
+use std::f64::consts::PI;
+
+/// A processing stage for the output, e.g., an equalizer.
+pub trait Filter: Send {
+    /// Process a stereo frame. Samples are in the range of i16, but may exceed it in between
+    /// filters.
+    fn process(&mut self, frame: [f32; 2]) -> [f32; 2];
+
+    /// Adapt to the sample rate of the output. This also resets the state of the filter. The chain
+    /// calls it before the first frame.
+    fn set_sample_rate(&mut self, sample_rate: u64);
+}
+
+/// Settings of a filter, e.g., for the config.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FilterSpec {
+    LowShelf {
+        frequency: f32,
+        gain_db: f32,
+    },
+    HighShelf {
+        frequency: f32,
+        gain_db: f32,
+    },
+    Peaking {
+        frequency: f32,
+        gain_db: f32,
+        q: f32,
+    },
+    HighPass {
+        frequency: f32,
+    },
+    Balance(f32),
+}
+
+impl FilterSpec {
+    pub fn build(&self) -> Box<dyn Filter> {
+        match *self {
+            FilterSpec::LowShelf { frequency, gain_db } => {
+                Box::new(Equalizer::low_shelf(frequency, gain_db))
+            }
+            FilterSpec::HighShelf { frequency, gain_db } => {
+                Box::new(Equalizer::high_shelf(frequency, gain_db))
+            }
+            FilterSpec::Peaking {
+                frequency,
+                gain_db,
+                q,
+            } => Box::new(Equalizer::peaking(frequency, gain_db, q)),
+            FilterSpec::HighPass { frequency } => Box::new(HighPass::new(frequency)),
+            FilterSpec::Balance(balance) => Box::new(Balance::new(balance)),
+        }
+    }
+}
+
+/// Filters that are applied one after the other to the output.
+pub struct FilterChain {
+    filters: Vec<Box<dyn Filter>>,
+    sample_rate: u64,
+}
+
+impl FilterChain {
+    pub fn new(sample_rate: u64) -> Self {
+        FilterChain {
+            filters: Vec::new(),
+            sample_rate,
+        }
+    }
+
+    /// Replace all filters of the chain.
+    pub fn set_filters(&mut self, filters: Vec<Box<dyn Filter>>) {
+        self.filters = filters;
+        for f in &mut self.filters {
+            f.set_sample_rate(self.sample_rate);
+        }
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u64) {
+        if sample_rate != self.sample_rate {
+            self.sample_rate = sample_rate;
+            for f in &mut self.filters {
+                f.set_sample_rate(sample_rate);
+            }
+        }
+    }
+
+    /// Filter the interleaved stereo samples in place.
+    pub fn process(&mut self, samples: &mut [i16]) {
+        if self.filters.is_empty() {
+            return;
+        }
+        for frame in samples.chunks_exact_mut(2) {
+            let mut f = [frame[0] as f32, frame[1] as f32];
+            for filter in &mut self.filters {
+                f = filter.process(f);
+            }
+            for (s, v) in frame.iter_mut().zip(&f) {
+                *s = v.round().max(i16::MIN as f32).min(i16::MAX as f32) as i16;
+            }
+        }
+    }
+}
+
+/// Q of the shelving filters, which gives the steepest slope without an overshoot.
+const SHELF_Q: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Band {
+    LowShelf,
+    HighShelf,
+    Peaking,
+}
+
+/// Normalize the coefficients of the biquad by a0.
+fn biquad(b: [f64; 3], a: [f64; 3]) -> Biquad {
+    Biquad::new(
+        [b[0] / a[0], b[1] / a[0], b[2] / a[0]],
+        [a[1] / a[0], a[2] / a[0]],
+    )
+}
+
+/// Cosine of the frequency (as an angle per sample) and alpha of a filter as defined by the
+/// "Audio EQ Cookbook" of Robert Bristow-Johnson.
+fn cookbook_params(frequency: f32, q: f32, sample_rate: u64) -> (f64, f64) {
+    let w0 = 2.0 * PI * frequency as f64 / sample_rate as f64;
+    (w0.cos(), w0.sin() / (2.0 * q as f64))
+}
+
+/// One band of an equalizer, which boosts or cuts the frequencies below or above (shelf) or
+/// around (peaking) the given frequency.
+pub struct Equalizer {
+    band: Band,
+    frequency: f32,
+    gain_db: f32,
+    q: f32,
+    filters: [Biquad; 2],
+}
+
+impl Equalizer {
+    fn new(band: Band, frequency: f32, gain_db: f32, q: f32) -> Self {
+        let identity = biquad([1.0, 0.0, 0.0], [1.0, 0.0, 0.0]);
+        Equalizer {
+            band,
+            frequency,
+            gain_db,
+            q,
+            filters: [identity.clone(), identity],
+        }
+    }
+
+    pub fn low_shelf(frequency: f32, gain_db: f32) -> Self {
+        Self::new(Band::LowShelf, frequency, gain_db, SHELF_Q)
+    }
+
+    pub fn high_shelf(frequency: f32, gain_db: f32) -> Self {
+        Self::new(Band::HighShelf, frequency, gain_db, SHELF_Q)
+    }
+
+    pub fn peaking(frequency: f32, gain_db: f32, q: f32) -> Self {
+        Self::new(Band::Peaking, frequency, gain_db, q)
+    }
+}
+
+impl Filter for Equalizer {
+    fn process(&mut self, frame: [f32; 2]) -> [f32; 2] {
+        [
+            self.filters[0].process(frame[0] as f64) as f32,
+            self.filters[1].process(frame[1] as f64) as f32,
+        ]
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: u64) {
+        let a = 10f64.powf(self.gain_db as f64 / 40.0);
+        let (cos, alpha) = cookbook_params(self.frequency, self.q, sample_rate);
+        let sa = 2.0 * a.sqrt() * alpha;
+        let filter = match self.band {
+            Band::LowShelf => biquad(
+                [
+                    a * ((a + 1.0) - (a - 1.0) * cos + sa),
+                    2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                    a * ((a + 1.0) - (a - 1.0) * cos - sa),
+                ],
+                [
+                    (a + 1.0) + (a - 1.0) * cos + sa,
+                    -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                    (a + 1.0) + (a - 1.0) * cos - sa,
+                ],
+            ),
+            Band::HighShelf => biquad(
+                [
+                    a * ((a + 1.0) + (a - 1.0) * cos + sa),
+                    -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                    a * ((a + 1.0) + (a - 1.0) * cos - sa),
+                ],
+                [
+                    (a + 1.0) - (a - 1.0) * cos + sa,
+                    2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                    (a + 1.0) - (a - 1.0) * cos - sa,
+                ],
+            ),
+            Band::Peaking => biquad(
+                [1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a],
+                [1.0 + alpha / a, -2.0 * cos, 1.0 - alpha / a],
+            ),
+        };
+        self.filters = [filter.clone(), filter];
+    }
+}
+
+/// Q of the two stages of a fourth order Butterworth filter
+const BUTTERWORTH_Q: [f32; 2] = [0.541_196_1, 1.306_563];
+
+/// Removes the frequencies below the cutoff (with 24dB per octave), which small speakers cannot
+/// play without distortion.
+pub struct HighPass {
+    frequency: f32,
+    /// Both stages for both channels
+    filters: [[Biquad; 2]; 2],
+}
+
+impl HighPass {
+    pub fn new(frequency: f32) -> Self {
+        let identity = biquad([1.0, 0.0, 0.0], [1.0, 0.0, 0.0]);
+        HighPass {
+            frequency,
+            filters: [
+                [identity.clone(), identity.clone()],
+                [identity.clone(), identity],
+            ],
+        }
+    }
+}
+
+impl Filter for HighPass {
+    fn process(&mut self, frame: [f32; 2]) -> [f32; 2] {
+        let mut out = [0.0; 2];
+        for ((o, s), stages) in out.iter_mut().zip(&frame).zip(&mut self.filters) {
+            let x = stages[0].process(*s as f64);
+            *o = stages[1].process(x) as f32;
+        }
+        out
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: u64) {
+        let stage = |q: f32| {
+            let (cos, alpha) = cookbook_params(self.frequency, q, sample_rate);
+            biquad(
+                [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0],
+                [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+            )
+        };
+        let stages = [stage(BUTTERWORTH_Q[0]), stage(BUTTERWORTH_Q[1])];
+        self.filters = [stages.clone(), stages];
+    }
+}
+
+/// Shifts the output to the left (-1.0) or right (1.0) channel by turning down the other one.
+pub struct Balance {
+    gains: [f32; 2],
+}
+
+impl Balance {
+    pub fn new(balance: f32) -> Self {
+        let balance = balance.clamp(-1.0, 1.0);
+        Balance {
+            gains: [(1.0 - balance).min(1.0), (1.0 + balance).min(1.0)],
+        }
+    }
+}
+
+impl Filter for Balance {
+    fn process(&mut self, frame: [f32; 2]) -> [f32; 2] {
+        [frame[0] * self.gains[0], frame[1] * self.gains[1]]
+    }
+
+    fn set_sample_rate(&mut self, _sample_rate: u64) {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Gain (in dB) of the filter for a sine of the given frequency in the left channel.
+    fn gain_db(spec: FilterSpec, frequency: f32) -> f32 {
+        let mut chain = FilterChain::new(44100);
+        chain.set_filters(vec![spec.build()]);
+        let amplitude = 10000.0;
+        let mut samples = (0..44100)
+            .flat_map(|i| {
+                let t = i as f32 / 44100.0;
+                let s = amplitude * (2.0 * std::f32::consts::PI * frequency * t).sin();
+                vec![s as i16, 0]
+            })
+            .collect::<Vec<_>>();
+        chain.process(&mut samples);
+        // Skip the transient at the beginning.
+        let peak = samples[44100..]
+            .iter()
+            .step_by(2)
+            .map(|s| s.saturating_abs())
+            .max()
+            .unwrap();
+        20.0 * (peak as f32 / amplitude).log10()
+    }
+
+    fn assert_gain(spec: FilterSpec, frequency: f32, expected_db: f32) {
+        let gain = gain_db(spec, frequency);
+        assert!((gain - expected_db).abs() < 0.2, "{:?}: {}", spec, gain);
+    }
+
+    #[test]
+    fn test_equalizer() {
+        let low_shelf = FilterSpec::LowShelf {
+            frequency: 200.0,
+            gain_db: 6.0,
+        };
+        assert_gain(low_shelf, 30.0, 6.0);
+        assert_gain(low_shelf, 5000.0, 0.0);
+
+        let high_shelf = FilterSpec::HighShelf {
+            frequency: 4000.0,
+            gain_db: -6.0,
+        };
+        assert_gain(high_shelf, 100.0, 0.0);
+        assert_gain(high_shelf, 18000.0, -6.0);
+
+        let peaking = FilterSpec::Peaking {
+            frequency: 1000.0,
+            gain_db: -9.0,
+            q: 2.0,
+        };
+        assert_gain(peaking, 1000.0, -9.0);
+        assert_gain(peaking, 100.0, 0.0);
+        assert_gain(peaking, 10000.0, 0.0);
+    }
+
+    #[test]
+    fn test_high_pass() {
+        let high_pass = FilterSpec::HighPass { frequency: 150.0 };
+        assert_gain(high_pass, 150.0, -3.0);
+        assert_gain(high_pass, 2000.0, 0.0);
+        assert!(gain_db(high_pass, 50.0) < -36.0);
+    }
+
+    #[test]
+    fn test_chain() {
+        let mut chain = FilterChain::new(44100);
+        let input = vec![1000, -1000, 20000, 30000];
+        let mut samples = input.clone();
+        chain.process(&mut samples);
+        assert_eq!(samples, input);
+
+        chain.set_filters(vec![FilterSpec::Balance(0.5).build()]);
+        chain.process(&mut samples);
+        assert_eq!(samples, vec![500, -1000, 10000, 30000]);
+
+        // Samples that exceed the range in between filters are only clipped at the end.
+        chain.set_filters(vec![
+            FilterSpec::LowShelf {
+                frequency: 20000.0,
+                gain_db: 6.0,
+            }
+            .build(),
+            FilterSpec::Balance(1.0).build(),
+        ]);
+        let mut samples = vec![i16::MAX; 2000];
+        chain.process(&mut samples);
+        assert_eq!(&samples[1998..], &[0, i16::MAX]);
+    }
+}
@@ -0,0 +1,111 @@
+use crate::media_definition::{self, CardDefinition, CardOptions, MediaDefinition, Target};
+use crate::rfid::Uid;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Whether the name can be written as a target in the media definition file without changing its
+/// meaning (surrounding whitespace is trimmed and a leading '@' denotes a command).
+fn representable(name: &str) -> bool {
+    !name.is_empty() && name.trim() == name && !name.starts_with('@') && !name.contains('\n')
+}
+
+/// The first file or directory of the inbox (in natural order) that is not yet the target of any
+/// card.
+pub fn next_unassigned(inbox: &Path, media: &MediaDefinition) -> io::Result<Option<PathBuf>> {
+    let assigned = media
+        .values()
+        .filter_map(CardDefinition::media_path)
+        .filter_map(|p| p.canonicalize().ok())
+        .collect::<Vec<_>>();
+
+    let mut candidates = Vec::new();
+    for entry in std::fs::read_dir(inbox)? {
+        let entry = entry?;
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        if name.starts_with('.') || !representable(&name) {
+            continue;
+        }
+        let path = entry.path();
+        if !path.is_dir() && !crate::decoder::is_playable(&path) {
+            continue;
+        }
+        match path.canonicalize() {
+            Ok(p) if !assigned.contains(&p) => candidates.push(name),
+            _ => {}
+        }
+    }
+    Ok(candidates
+        .into_iter()
+        .min_by(|l, r| crate::playlist::natural_cmp(l, r))
+        .map(|name| inbox.join(name)))
+}
+
+/// Assign the card to the next unassigned inbox entry. The mapping is appended to the media
+/// definition file and added to `media` right away. Returns the newly assigned media (if there
+/// was any left).
+pub fn learn_card(
+    uid: Uid,
+    map_definition_file: &Path,
+    media_file_root: &Path,
+    media: &mut MediaDefinition,
+) -> io::Result<Option<PathBuf>> {
+    let inbox = media_file_root.join(crate::config::INBOX_DIR);
+    let path = match next_unassigned(&inbox, media)? {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    // next_unassigned only returns entries with valid unicode names.
+    let name = path.file_name().unwrap().to_string_lossy();
+    let target = format!("{}/{}", crate::config::INBOX_DIR, name);
+    media_definition::append_card(map_definition_file, uid, &target)?;
+    media.insert(
+        uid,
+        CardDefinition {
+            target: Target::Media(path.clone()),
+            options: CardOptions::default(),
+        },
+    );
+    Ok(Some(path))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_learn_card() {
+        let root = std::env::temp_dir().join(format!("kassette_learn_{}", std::process::id()));
+        let inbox = root.join(crate::config::INBOX_DIR);
+        std::fs::create_dir_all(inbox.join("album 10")).unwrap();
+        std::fs::create_dir_all(inbox.join("album 9")).unwrap();
+        std::fs::create_dir_all(inbox.join(".hidden")).unwrap();
+        std::fs::write(inbox.join("notes.txt"), b"").unwrap();
+        std::fs::write(inbox.join("song.ogg"), b"").unwrap();
+        let definition_file = root.join("media_definition.txt");
+        std::fs::write(&definition_file, "0x1 inbox/album 9").unwrap();
+
+        let mut media = media_definition::load_media_definition(&definition_file, &root)
+            .unwrap()
+            .media;
+        assert_eq!(
+            learn_card(Uid::from_legacy(2), &definition_file, &root, &mut media).unwrap(),
+            Some(inbox.join("album 10"))
+        );
+        assert_eq!(
+            learn_card(Uid::from_legacy(3), &definition_file, &root, &mut media).unwrap(),
+            Some(inbox.join("song.ogg"))
+        );
+        assert_eq!(
+            learn_card(Uid::from_legacy(4), &definition_file, &root, &mut media).unwrap(),
+            None
+        );
+
+        let reloaded = media_definition::load_media_definition(&definition_file, &root).unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+        assert!(reloaded.diagnostics.is_empty());
+        assert_eq!(reloaded.media, media);
+    }
+}
@@ -9,6 +9,8 @@ pub struct Led {
 pub enum LedCommand {
     Blink(Duration),
     DoubleBlink(Duration, Duration, Duration),
+    /// Blink the given number of times with equal on and off durations.
+    Flash(u32, Duration),
 }
 
 impl Led {
@@ -42,6 +44,16 @@ impl Led {
                 std::thread::sleep(on2);
                 self.off();
             }
+            LedCommand::Flash(count, len) => {
+                for i in 0..count {
+                    if i > 0 {
+                        std::thread::sleep(len);
+                    }
+                    self.on();
+                    std::thread::sleep(len);
+                    self.off();
+                }
+            }
         }
     }
 }
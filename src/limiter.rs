@@ -0,0 +1,134 @@
+use crate::loudness::{k_weighting, loudness_to_energy, Biquad};
+use std::time::Duration;
+
+/// The loudness is estimated from averages over the windows of the momentary and the short-term
+/// loudness of EBU R128. The larger one wins, so that it follows a rising signal quickly and a
+/// falling one slowly.
+const MOMENTARY_TIME: Duration = Duration::from_millis(400);
+const SHORT_TERM_TIME: Duration = Duration::from_secs(3);
+/// Time that the peak limiter takes to recover from the largest possible gain reduction.
+const PEAK_RELEASE_TIME: Duration = Duration::from_millis(500);
+
+/// Keeps the output below a sample peak and a short-term loudness, whatever the volume and the
+/// media are. Loud passages are turned down smoothly, peaks are cut instantly.
+pub struct Limiter {
+    max_peak: f32,
+    /// The maximum loudness as K-weighted energy, which avoids a logarithm for every frame
+    max_energy: f64,
+    filters: [[Biquad; 2]; 2],
+    /// Running averages of the K-weighted energy of both channels
+    momentary_energy: f64,
+    short_term_energy: f64,
+    momentary_weight: f64,
+    short_term_weight: f64,
+    peak_gain: f32,
+    peak_release: f32,
+}
+
+impl Limiter {
+    pub fn new(sample_rate: u64) -> Self {
+        let frames = |d: Duration| d.as_secs_f64() * sample_rate as f64;
+        Limiter {
+            max_peak: 10f32.powf(crate::config::MAX_OUTPUT_PEAK / 20.0) * i16::MAX as f32,
+            max_energy: loudness_to_energy(crate::config::MAX_OUTPUT_LOUDNESS as f64),
+            filters: [
+                k_weighting(sample_rate as f64),
+                k_weighting(sample_rate as f64),
+            ],
+            momentary_energy: 0.0,
+            short_term_energy: 0.0,
+            momentary_weight: 1.0 / frames(MOMENTARY_TIME),
+            short_term_weight: 1.0 / frames(SHORT_TERM_TIME),
+            peak_gain: 1.0,
+            peak_release: 1.0 / frames(PEAK_RELEASE_TIME) as f32,
+        }
+    }
+
+    /// Limit the interleaved stereo samples in place.
+    pub fn process(&mut self, samples: &mut [i16]) {
+        for frame in samples.chunks_exact_mut(2) {
+            let mut energy = 0.0;
+            for (s, [shelf, high_pass]) in frame.iter().zip(self.filters.iter_mut()) {
+                let y = high_pass.process(shelf.process(*s as f64 / 32768.0));
+                energy += y * y;
+            }
+            self.momentary_energy += (energy - self.momentary_energy) * self.momentary_weight;
+            self.short_term_energy += (energy - self.short_term_energy) * self.short_term_weight;
+
+            // Loudness is proportional to the logarithm of the energy, so reducing it by the
+            // excess (in dB) means scaling the samples by the root of the energy ratio.
+            let energy = self.momentary_energy.max(self.short_term_energy);
+            let loudness_gain = if energy > self.max_energy {
+                (self.max_energy / energy).sqrt() as f32
+            } else {
+                1.0
+            };
+
+            let peak = frame
+                .iter()
+                .map(|s| (*s as f32 * loudness_gain).abs())
+                .fold(0.0, f32::max);
+            self.peak_gain = (self.peak_gain + self.peak_release)
+                .min(1.0)
+                .min(self.max_peak / peak);
+
+            let gain = loudness_gain * self.peak_gain;
+            if gain < 1.0 {
+                for s in frame.iter_mut() {
+                    // Rounded towards zero to stay below the maximum peak
+                    *s = (*s as f32 * gain) as i16;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sine(amplitude: f32, secs: f32) -> Vec<i16> {
+        (0..(44100.0 * secs) as usize)
+            .flat_map(|i| {
+                let t = i as f32 / 44100.0;
+                let s = (amplitude * (2.0 * std::f32::consts::PI * 1000.0 * t).sin()) as i16;
+                vec![s; 2]
+            })
+            .collect()
+    }
+
+    fn peak(samples: &[i16]) -> i16 {
+        samples.iter().map(|s| s.saturating_abs()).max().unwrap()
+    }
+
+    #[test]
+    fn test_quiet_signal() {
+        let input = sine(1000.0, 1.0);
+        let mut output = input.clone();
+        Limiter::new(44100).process(&mut output);
+        assert_eq!(input, output);
+    }
+
+    #[test]
+    fn test_peak() {
+        let mut limiter = Limiter::new(44100);
+        let max = limiter.max_peak as i16;
+        let mut samples = vec![i16::MAX; 200];
+        samples.extend(vec![i16::MIN; 200]);
+        limiter.process(&mut samples);
+        assert!(peak(&samples) <= max);
+        assert!(samples[0] >= max - 1);
+    }
+
+    #[test]
+    fn test_loudness() {
+        // A 1kHz sine at full scale in both channels has a loudness of 0 LUFS.
+        let mut samples = sine(i16::MAX as f32, 10.0);
+        Limiter::new(44100).process(&mut samples);
+        let expected = 10f32.powf(crate::config::MAX_OUTPUT_LOUDNESS / 20.0) * i16::MAX as f32;
+        let end = peak(&samples[samples.len() - 44100..]) as f32;
+        assert!((end / expected - 1.0).abs() < 0.05, "{} {}", end, expected);
+        // The loudness is turned down within the first second.
+        assert!((peak(&samples[44100..2 * 44100]) as f32) < 2.0 * expected);
+    }
+}
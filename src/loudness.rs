@@ -0,0 +1,442 @@
+use crate::decoder::{DecodeError, Decoder};
+use crate::metadata::find_tag;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+
+/// Gain that brings a track to the target loudness.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TrackGain {
+    /// Gain in dB.
+    pub gain: f32,
+    /// Highest absolute sample value of the track (1.0 is full scale), if known.
+    pub peak: Option<f32>,
+}
+
+impl TrackGain {
+    /// Read the gain from ReplayGain tags ("REPLAYGAIN_TRACK_GAIN=-6.20 dB") or the R128 tag of
+    /// opus files ("R128_TRACK_GAIN=-1536", in 1/256 dB relative to -23 LUFS).
+    pub fn from_tags(comments: &[(String, String)]) -> Option<Self> {
+        let replay_gain = find_tag(comments, "REPLAYGAIN_TRACK_GAIN")
+            .or_else(|| find_tag(comments, "REPLAY_GAIN_TRACK_GAIN"));
+        if let Some(gain) = replay_gain {
+            let gain = gain.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+            let peak = find_tag(comments, "REPLAYGAIN_TRACK_PEAK")
+                .or_else(|| find_tag(comments, "REPLAY_GAIN_TRACK_PEAK"))
+                .and_then(|p| p.parse().ok());
+            return Some(TrackGain {
+                gain: gain.trim().parse().ok()?,
+                peak,
+            });
+        }
+        let r128_gain = find_tag(comments, "R128_TRACK_GAIN")?.parse::<i16>().ok()?;
+        Some(TrackGain {
+            gain: r128_gain as f32 / 256.0 + crate::config::TARGET_LOUDNESS - R128_REFERENCE,
+            peak: None,
+        })
+    }
+
+    fn from_loudness(loudness: Option<f64>, peak: f32) -> Self {
+        TrackGain {
+            // There is nothing to normalize in a silent track.
+            gain: loudness
+                .map(|l| crate::config::TARGET_LOUDNESS - l as f32)
+                .unwrap_or(0.0),
+            peak: Some(peak),
+        }
+    }
+
+    fn parse_cache(s: &str) -> Option<Self> {
+        let mut gain = None;
+        let mut peak = None;
+        for line in s.lines() {
+            let (key, value) = line.split_at(line.find('=')?);
+            let value = value[1..].trim();
+            match key.trim() {
+                "gain" => gain = Some(value.parse().ok()?),
+                "peak" => peak = Some(value.parse().ok()?),
+                _ => {}
+            }
+        }
+        Some(TrackGain { gain: gain?, peak })
+    }
+
+    fn to_cache(self) -> String {
+        match self.peak {
+            Some(peak) => format!("gain={:.2}\npeak={:.6}\n", self.gain, peak),
+            None => format!("gain={:.2}\n", self.gain),
+        }
+    }
+
+    /// Linear factor for the samples of the track. Boosts are limited so that the peak of the
+    /// track does not clip.
+    pub fn factor(&self) -> f32 {
+        let factor = 10f32.powf(self.gain.min(crate::config::MAX_TRACK_GAIN) / 20.0);
+        match self.peak {
+            Some(peak) if peak > 0.0 => factor.min((1.0 / peak).max(1.0)),
+            _ => factor,
+        }
+    }
+}
+
+/// Loudness that the R128 gain tags refer to (in LUFS).
+const R128_REFERENCE: f32 = -23.0;
+
+/// Apply a linear gain factor to a sample. Samples that would clip are limited to full scale.
+pub fn apply_gain(sample: i16, factor: f32) -> i16 {
+    (sample as f32 * factor)
+        .round()
+        .max(i16::MIN as f32)
+        .min(i16::MAX as f32) as i16
+}
+
+/// Second order IIR filter in transposed direct form II.
+#[derive(Clone)]
+pub struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    z: [f64; 2],
+}
+
+impl Biquad {
+    pub fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Biquad { b, a, z: [0.0; 2] }
+    }
+
+    pub fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[0] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+/// The two filter stages of the K-weighting of ITU-R BS.1770 (a high shelf that models the head
+/// and a high pass), derived for the given sample rate.
+pub fn k_weighting(sample_rate: f64) -> [Biquad; 2] {
+    use std::f64::consts::PI;
+
+    let f0 = 1681.974450955533;
+    let gain = 3.999843853973347;
+    let q = 0.7071752369554196;
+    let k = (PI * f0 / sample_rate).tan();
+    let vh = 10f64.powf(gain / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad::new(
+        [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (PI * f0 / sample_rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad::new(
+        [1.0, -2.0, 1.0],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+    [shelf, high_pass]
+}
+
+fn energy_to_loudness(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+pub fn loudness_to_energy(loudness: f64) -> f64 {
+    10f64.powf((loudness + 0.691) / 10.0)
+}
+
+/// Measures the integrated loudness of a stream as specified by EBU R128, i.e., the gated mean of
+/// the K-weighted energy of overlapping blocks of 400ms.
+struct LoudnessMeter {
+    channels: usize,
+    filters: Vec<[Biquad; 2]>,
+    // Blocks overlap by 75%, so we collect the energy of quarter blocks.
+    quarter_len: usize,
+    quarter_pos: usize,
+    quarter_energy: f64,
+    quarters: Vec<f64>,
+    peak: i16,
+}
+
+impl LoudnessMeter {
+    fn new(sample_rate: u32, channels: usize) -> Self {
+        LoudnessMeter {
+            channels,
+            filters: vec![k_weighting(sample_rate as f64); channels],
+            quarter_len: (sample_rate as usize / 10).max(1),
+            quarter_pos: 0,
+            quarter_energy: 0.0,
+            quarters: Vec::new(),
+            peak: 0,
+        }
+    }
+
+    /// Add interleaved samples.
+    fn push(&mut self, samples: &[i16]) {
+        for frame in samples.chunks_exact(self.channels) {
+            for (s, [shelf, high_pass]) in frame.iter().zip(self.filters.iter_mut()) {
+                self.peak = self.peak.max(s.saturating_abs());
+                let x = *s as f64 / 32768.0;
+                let y = high_pass.process(shelf.process(x));
+                self.quarter_energy += y * y;
+            }
+            self.quarter_pos += 1;
+            if self.quarter_pos == self.quarter_len {
+                self.quarters
+                    .push(self.quarter_energy / self.quarter_len as f64);
+                self.quarter_pos = 0;
+                self.quarter_energy = 0.0;
+            }
+        }
+    }
+
+    /// The integrated loudness in LUFS, if there is any audible signal.
+    fn integrated_loudness(&self) -> Option<f64> {
+        const ABSOLUTE_GATE: f64 = -70.0;
+        const RELATIVE_GATE: f64 = -10.0;
+
+        let blocks = self
+            .quarters
+            .windows(4)
+            .map(|w| w.iter().sum::<f64>() / 4.0)
+            .filter(|e| energy_to_loudness(*e) > ABSOLUTE_GATE)
+            .collect::<Vec<_>>();
+        if blocks.is_empty() {
+            return None;
+        }
+        let mean = blocks.iter().sum::<f64>() / blocks.len() as f64;
+        let gate = energy_to_loudness(mean) + RELATIVE_GATE;
+
+        let gated = blocks
+            .iter()
+            .filter(|e| energy_to_loudness(**e) > gate)
+            .collect::<Vec<_>>();
+        let mean = gated.iter().copied().sum::<f64>() / gated.len() as f64;
+        Some(energy_to_loudness(mean))
+    }
+
+    fn peak(&self) -> f32 {
+        self.peak as f32 / 32768.0
+    }
+}
+
+/// Decode the whole stream and measure its loudness.
+pub fn analyze(decoder: &mut dyn Decoder) -> Result<TrackGain, DecodeError> {
+    let info = decoder.info();
+    let mut meter = LoudnessMeter::new(info.sample_rate, info.channels);
+    while let Some(packet) = decoder.next_packet()? {
+        meter.push(&packet);
+    }
+    Ok(TrackGain::from_loudness(
+        meter.integrated_loudness(),
+        meter.peak(),
+    ))
+}
+
+/// The file that caches the result of the analysis of the given media file.
+fn cache_path(media_path: &Path) -> Option<PathBuf> {
+    let name = media_path.file_name()?.to_string_lossy();
+    Some(media_path.with_file_name(format!(".{}.gain", name)))
+}
+
+fn read_cache(media_path: &Path) -> Option<TrackGain> {
+    let s = std::fs::read_to_string(cache_path(media_path)?).ok()?;
+    TrackGain::parse_cache(&s)
+}
+
+fn analyze_and_cache(media_path: &Path) -> Result<TrackGain, DecodeError> {
+    let mut decoder = crate::decoder::open(media_path)?;
+    let gain = analyze(&mut *decoder)?;
+    if let Some(cache_path) = cache_path(media_path) {
+        std::fs::write(cache_path, gain.to_cache())?;
+    }
+    Ok(gain)
+}
+
+/// Lower the priority of the calling thread. On linux, the nice value is per thread.
+fn lower_thread_priority() -> std::io::Result<()> {
+    let niceness = crate::config::ANALYZER_NICENESS;
+    match unsafe { libc::setpriority(libc::PRIO_PROCESS as _, 0, niceness) } {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+/// Measures the loudness of tracks without gain tags in the background, so that the gain is known
+/// the next time they are played.
+pub struct Analyzer {
+    requests: mpsc::Sender<PathBuf>,
+}
+
+impl Analyzer {
+    pub fn new() -> Self {
+        let (requests, pending) = mpsc::channel::<PathBuf>();
+        std::thread::Builder::new()
+            .name("loudness_thread".to_owned())
+            .spawn(move || {
+                // The analysis decodes whole tracks as fast as possible, which must not starve the
+                // playback on a single core.
+                log_err!(
+                    "Lower the priority of the loudness thread",
+                    lower_thread_priority()
+                );
+                // Do not retry tracks that failed (e.g., because the file system is read-only).
+                let mut done = HashSet::new();
+                for path in pending {
+                    if done.contains(&path) || read_cache(&path).is_some() {
+                        continue;
+                    }
+                    match analyze_and_cache(&path) {
+                        Ok(gain) => log!("Measured gain of {:?}: {:.2}dB", path, gain.gain),
+                        Err(e) => log!("Unable to measure loudness of {:?}: {}", path, e),
+                    }
+                    done.insert(path);
+                }
+            })
+            .unwrap();
+        Analyzer { requests }
+    }
+
+    /// The gain of the track from its tags or from a previous analysis. If neither is available,
+    /// the track is queued for analysis.
+    pub fn track_gain(
+        &self,
+        media_path: &Path,
+        comments: &[(String, String)],
+    ) -> Option<TrackGain> {
+        let gain = TrackGain::from_tags(comments).or_else(|| read_cache(media_path));
+        if gain.is_none() {
+            // The thread only stops when the analyzer is dropped.
+            let _ = self.requests.send(media_path.to_owned());
+        }
+        gain
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tags(c: &[(&str, &str)]) -> Vec<(String, String)> {
+        c.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn sine(sample_rate: u32, channels: usize, amplitude: f64, secs: f64) -> Vec<i16> {
+        let frames = (sample_rate as f64 * secs) as usize;
+        (0..frames)
+            .flat_map(|i| {
+                let t = i as f64 / sample_rate as f64;
+                let s =
+                    (amplitude * 32767.0 * (2.0 * std::f64::consts::PI * 997.0 * t).sin()) as i16;
+                vec![s; channels]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_gain_from_tags() {
+        assert_eq!(
+            TrackGain::from_tags(&tags(&[
+                ("replaygain_track_gain", "-6.20 dB"),
+                ("REPLAYGAIN_TRACK_PEAK", "0.988"),
+            ])),
+            Some(TrackGain {
+                gain: -6.2,
+                peak: Some(0.988)
+            })
+        );
+        assert_eq!(
+            TrackGain::from_tags(&tags(&[("REPLAY_GAIN_TRACK_GAIN", "+2.5dB")])),
+            Some(TrackGain {
+                gain: 2.5,
+                peak: None
+            })
+        );
+        // -6dB relative to -23 LUFS
+        let expected = -6.0 + crate::config::TARGET_LOUDNESS + 23.0;
+        assert_eq!(
+            TrackGain::from_tags(&tags(&[("R128_TRACK_GAIN", "-1536")])),
+            Some(TrackGain {
+                gain: expected,
+                peak: None
+            })
+        );
+        assert_eq!(TrackGain::from_tags(&tags(&[("TITLE", "Momo")])), None);
+        assert_eq!(
+            TrackGain::from_tags(&tags(&[("REPLAYGAIN_TRACK_GAIN", "loud")])),
+            None
+        );
+    }
+
+    #[test]
+    fn test_factor() {
+        let gain = |gain, peak| TrackGain { gain, peak }.factor();
+        assert!((gain(-6.0206, None) - 0.5).abs() < 1e-4);
+        assert!((gain(6.0206, None) - 2.0).abs() < 1e-4);
+        // Boosts are limited by the peak, attenuation is not.
+        assert!((gain(6.0206, Some(0.8)) - 1.25).abs() < 1e-4);
+        assert!((gain(-6.0206, Some(1.0)) - 0.5).abs() < 1e-4);
+        assert!(gain(100.0, None) <= 10f32.powf(crate::config::MAX_TRACK_GAIN / 20.0));
+
+        assert_eq!(apply_gain(1000, 2.0), 2000);
+        assert_eq!(apply_gain(30000, 2.0), i16::MAX);
+        assert_eq!(apply_gain(-30000, 2.0), i16::MIN);
+    }
+
+    #[test]
+    fn test_cache_format() {
+        let gain = TrackGain {
+            gain: -3.25,
+            peak: Some(0.5),
+        };
+        assert_eq!(TrackGain::parse_cache(&gain.to_cache()), Some(gain));
+        assert_eq!(
+            TrackGain::parse_cache("gain=1.5\n"),
+            Some(TrackGain {
+                gain: 1.5,
+                peak: None
+            })
+        );
+        assert_eq!(TrackGain::parse_cache("peak=1.0\n"), None);
+        assert_eq!(TrackGain::parse_cache("gain\n"), None);
+    }
+
+    #[test]
+    fn test_loudness() {
+        // A full scale 1kHz sine on one channel has a loudness of -3.01 LUFS, the level of a
+        // stereo signal is the sum of both channels.
+        for &(sample_rate, channels, amplitude, expected) in &[
+            (48000, 1, 1.0, -3.01),
+            (44100, 1, 0.1, -23.01),
+            (44100, 2, 0.1, -20.0),
+        ] {
+            let mut meter = LoudnessMeter::new(sample_rate, channels);
+            meter.push(&sine(sample_rate, channels, amplitude, 3.0));
+            let loudness = meter.integrated_loudness().unwrap();
+            assert!(
+                (loudness - expected).abs() < 0.1,
+                "{} != {}",
+                loudness,
+                expected
+            );
+        }
+
+        // Silence does not count.
+        let mut meter = LoudnessMeter::new(44100, 2);
+        meter.push(&vec![0; 2 * 44100]);
+        assert_eq!(meter.integrated_loudness(), None);
+        // The blocks at the transitions from and to silence are partially gated.
+        meter.push(&sine(44100, 2, 0.1, 20.0));
+        meter.push(&vec![0; 10 * 2 * 44100]);
+        assert!((meter.integrated_loudness().unwrap() + 20.0).abs() < 0.1);
+        assert!((meter.peak() - 0.1).abs() < 1e-3);
+    }
+}
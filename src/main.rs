@@ -1,18 +1,35 @@
+use crate::earcon::Earcon;
+use crate::media_definition::Command;
+use crate::player::PlaybackPos;
 use crate::rfid::Uid;
 use argh::FromArgs;
 use std::path::{Path, PathBuf};
 use std::sync::mpsc;
 use std::time::{Duration, Instant, SystemTime};
 
+mod chapters;
 mod config;
 #[macro_use]
 mod log;
+mod decoder;
+mod downmix;
+mod earcon;
+mod fade;
+mod file_watch;
+mod filter;
+mod learn;
 mod led;
+mod limiter;
+mod loudness;
 mod media_definition;
+mod metadata;
 mod pins;
 mod player;
+mod playlist;
 mod polyfill;
+mod resampler;
 mod rfid;
+mod ring_buffer;
 mod rotary_encoder;
 mod save_state;
 mod sound;
@@ -102,6 +119,45 @@ enum Event {
     IncreaseVolume,
     DecreaseVolume,
     Shutdown,
+    SleepTimer(Duration),
+    SetVolume(player::Volume),
+    SetMaxVolume(player::Volume),
+    Next,
+    NextChapter,
+    PreviousChapter,
+    Shuffle,
+    ToggleLock,
+    ToggleLearnMode,
+    MediaDefinitionChanged(
+        media_definition::MediaDefinition,
+        Option<Vec<filter::FilterSpec>>,
+    ),
+}
+
+impl Event {
+    /// Events of command cards are handled the same way as those of the other inputs.
+    fn from_command(command: &Command) -> Self {
+        match command {
+            Command::Learn => Event::ToggleLearnMode,
+            Command::Shutdown => Event::Shutdown,
+            Command::SleepTimer(d) => Event::SleepTimer(*d),
+            Command::Volume(v) => Event::SetVolume(*v),
+            Command::MaxVolume(v) => Event::SetMaxVolume(*v),
+            Command::Next => Event::Next,
+            Command::NextChapter => Event::NextChapter,
+            Command::PreviousChapter => Event::PreviousChapter,
+            Command::Shuffle => Event::Shuffle,
+            Command::Lock => Event::ToggleLock,
+        }
+    }
+
+    /// Whether the event is ignored while the controls are locked.
+    fn blocked_by_lock(&self) -> bool {
+        !matches!(
+            self,
+            Event::Shutdown | Event::ToggleLock | Event::MediaDefinitionChanged(_, _)
+        )
+    }
 }
 
 enum CardState {
@@ -123,6 +179,124 @@ fn resume_rewind_time(stop_time: Duration) -> Duration {
     context + 2 * config::FADE_TIME
 }
 
+/// Log the current chapter (if the track has chapters).
+fn log_chapter(player: &player::Player) {
+    if let Some(i) = player.current_chapter() {
+        match player.chapters()[i].name {
+            Some(ref name) => log!("Chapter {}: {}", i + 1, name),
+            None => log!("Chapter {}", i + 1),
+        }
+    }
+}
+
+/// Go back a bit for context before continuing playback that was paused at remove_time.
+fn rewind_for_resume(player: &mut player::Player, remove_time: SystemTime) {
+    let stop_time = SystemTime::now()
+        .duration_since(remove_time)
+        .unwrap_or(Duration::from_millis(0));
+    log_err!(
+        "Rewind from remove time",
+        player.rewind(resume_rewind_time(stop_time))
+    );
+}
+
+fn shuffle_seed() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+/// Position to continue at for a card that has been stopped at pos at the given time.
+fn resume_pos(pos: PlaybackPos, stop_time: SystemTime) -> PlaybackPos {
+    let stop_duration = SystemTime::now()
+        .duration_since(stop_time)
+        .unwrap_or(Duration::from_millis(0));
+    pos.rewind(resume_rewind_time(stop_duration))
+}
+
+/// Remember where the player is in the media of the given card, so that it can be resumed later.
+fn store_resume_point(
+    save_state: &mut save_state::SaveState,
+    player: &player::Player,
+    uid: Uid,
+    stop_time: SystemTime,
+) {
+    match (player.track(), player.playback_pos()) {
+        (Some(track), Some(pos)) => save_state.set_resume_point(uid, track, pos, stop_time),
+        // Played to the end, so we start from the beginning next time.
+        _ => save_state.clear_resume_point(uid),
+    }
+}
+
+/// The filters of the media definition or the default ones.
+fn build_filters(specs: Option<&[filter::FilterSpec]>) -> Vec<Box<dyn filter::Filter>> {
+    specs
+        .unwrap_or(config::FILTERS)
+        .iter()
+        .map(|f| f.build())
+        .collect()
+}
+
+fn log_diagnostics(result: &media_definition::ParseResult) {
+    for d in &result.diagnostics {
+        log!("{} {}", config::MEDIA_DEFINITION_FILE, d);
+    }
+}
+
+/// Load the media of the card into the player. Playback starts at the resume point if there is
+/// one and the card options allow it. Returns false (and keeps the current media) if the media
+/// cannot be loaded.
+fn load_card(
+    player: &mut player::Player,
+    card: &media_definition::CardDefinition,
+    resume_point: Option<(usize, PlaybackPos)>,
+) -> bool {
+    let path = match card.media_path() {
+        Some(path) => path,
+        None => return false,
+    };
+    let options = &card.options;
+    let mut playlist = match playlist::Playlist::load(path) {
+        Ok(playlist) => playlist,
+        Err(e) => {
+            log!("Cannot read media {:?}: {}", path, e);
+            return false;
+        }
+    };
+    let resume_point = if options.shuffle {
+        playlist.shuffle(shuffle_seed());
+        None
+    } else if options.resume {
+        resume_point
+    } else {
+        None
+    };
+    let (track, start_pos) = match resume_point {
+        Some((track, pos)) => (track, Some(pos)),
+        None if options.start_offset > Duration::from_secs(0) => (
+            0,
+            Some(PlaybackPos::from_millis(
+                options.start_offset.as_millis() as u64
+            )),
+        ),
+        None => (0, None),
+    };
+
+    if let Err(e) = player.load(playlist, track, start_pos) {
+        log!("Load media for card: {}", e);
+        return false;
+    }
+    player.set_looping(options.looping);
+    player.set_volume_offset(options.volume_offset);
+    player.set_fade_time(options.fade.unwrap_or(config::FADE_TIME));
+    player.set_fade_curve(options.fade_curve.unwrap_or(config::FADE_CURVE));
+    if let Some(now_playing) = player.now_playing() {
+        log!("Loaded {}", now_playing);
+    }
+    true
+}
+
 fn main() {
     // Enable backtraces in case of a crash.
     std::env::set_var("RUST_BACKTRACE", "1");
@@ -159,10 +333,13 @@ fn data_root() -> &'static Path {
 
 fn run(options: Options) {
     let data_root = data_root();
-    let file_map = media_definition::load_media_definition(
-        data_root.join(config::MEDIA_DEFINITION_FILE),
-        data_root,
-    );
+    let media_definition_path = data_root.join(config::MEDIA_DEFINITION_FILE);
+    let mut media_definition_watcher = file_watch::FileWatcher::new(&media_definition_path);
+    let media_definition =
+        media_definition::load_media_definition(&media_definition_path, data_root).unwrap(); // If this fails we cannot do anything anyways.
+    log_diagnostics(&media_definition);
+    let mut file_map = media_definition.media;
+    let mut filters = media_definition.filters;
     let save_state_path = data_root.join(config::SAVESTATE_FILE);
 
     let gpio = rppal::gpio::Gpio::new().unwrap();
@@ -173,8 +350,33 @@ fn run(options: Options) {
     let (event_sink, event_source) = mpsc::channel();
     let rfid_event_sink = event_sink.clone();
     let rotary_encoder_event_sink = event_sink.clone();
+    let media_definition_event_sink = event_sink.clone();
     let shutdown_event_sink = event_sink;
 
+    let watched_media_definition_path = media_definition_path.clone();
+    let _media_definition_thread = std::thread::Builder::new()
+        .name("media_definition_thread".to_owned())
+        .spawn(move || loop {
+            media_definition_watcher.wait_for_change();
+            log!("{} changed, reloading", config::MEDIA_DEFINITION_FILE);
+            match media_definition::load_media_definition(&watched_media_definition_path, data_root)
+            {
+                Ok(result) => {
+                    log_diagnostics(&result);
+                    if result.has_errors() {
+                        log!("Keeping previous media definition");
+                        continue;
+                    }
+                    let event = Event::MediaDefinitionChanged(result.media, result.filters);
+                    if media_definition_event_sink.send(event).is_err() {
+                        break; // Main loop has finished
+                    }
+                }
+                Err(e) => log!("Cannot read {}: {}", config::MEDIA_DEFINITION_FILE, e),
+            }
+        })
+        .unwrap();
+
     let _rfid_thread = std::thread::Builder::new()
         .name("card_event_thread".to_owned())
         .spawn(move || {
@@ -223,6 +425,8 @@ fn run(options: Options) {
 
     let out = sound::AudioOutput::new();
     let mut player = player::Player::new(out, save_state.volume());
+    player.set_max_volume(save_state.max_volume());
+    player.set_filters(build_filters(filters.as_deref()));
 
     let mut sw = gpio
         .get(pins::ROTARY_ENCODER_SWITCH)
@@ -236,24 +440,59 @@ fn run(options: Options) {
 
     let mut card_state = CardState::Nothing;
     let mut silence_begin = Some(Instant::now());
-
-    if let Some((uid, pos, stop_time)) = save_state.playback_state() {
+    // The next unknown card will be assigned to media from the inbox.
+    let mut learn_mode = false;
+    // Only the lock card and shutdown work while the controls are locked.
+    let mut locked = false;
+    // Removing a command card does not pause playback.
+    let mut command_card_present = false;
+    let mut sleep_deadline: Option<Instant> = None;
+    let mut sleep_timer_expired = false;
+
+    if let Some((uid, track, pos, stop_time)) = save_state.playback_state() {
         card_state = CardState::Previous(uid, stop_time);
-        if let Some(file) = file_map.get(&uid) {
-            log_err!("Load initial file", player.load_file(file, Some(pos)));
+        if let Some(card) = file_map.get(&uid) {
+            load_card(&mut player, card, Some((track, pos)));
         } else {
-            log!("Cannot load unknown uid: {:x}", uid.0);
+            log!("Cannot load unknown uid: {}", uid);
         }
     }
 
     let mut stopped = false;
     while !(stopped && !player.playing()) {
-        match event_source.try_recv() {
+        let mut event = event_source.try_recv();
+        if let Ok(Event::Play(uid)) = event {
+            let command = file_map.get(&uid).and_then(|c| c.command());
+            command_card_present = command.is_some();
+            if let Some(command) = command {
+                log!("Command card: {}", command);
+                // The card of the media was removed to place the command card, but we want to
+                // keep listening (e.g., to the audio book that the sleep timer was set for).
+                let keeps_playing = !matches!(command, Command::Shutdown | Command::Learn);
+                if let CardState::Previous(old_uid, remove_time) = card_state {
+                    if keeps_playing && !locked && !player.idle() {
+                        rewind_for_resume(&mut player, remove_time);
+                        player.play();
+                        card_state = CardState::Current(old_uid);
+                    }
+                }
+                event = Ok(Event::from_command(command));
+            }
+        }
+        if let Ok(ref e) = event {
+            if locked && e.blocked_by_lock() {
+                log!("Controls are locked, ignoring event");
+                event = Err(mpsc::TryRecvError::Empty);
+            }
+        }
+        match event {
             Ok(Event::IncreaseVolume) => {
                 led_cmd_sink
                     .send(led::LedCommand::Blink(Duration::from_millis(5)))
                     .unwrap();
-                *player.volume() += 1;
+                let mut volume = player.volume();
+                volume += 1;
+                player.set_volume(volume);
             }
             Ok(Event::DecreaseVolume) => {
                 led_cmd_sink
@@ -263,39 +502,166 @@ fn run(options: Options) {
                         Duration::from_millis(5),
                     ))
                     .unwrap();
-                *player.volume() -= 1;
+                let mut volume = player.volume();
+                volume -= 1;
+                player.set_volume(volume);
+            }
+            Ok(Event::SetVolume(v)) => {
+                led_cmd_sink
+                    .send(led::LedCommand::Blink(Duration::from_millis(5)))
+                    .unwrap();
+                player.set_volume(v);
+            }
+            Ok(Event::SetMaxVolume(v)) => {
+                log!(
+                    "Maximum volume set to {}dB",
+                    v.db().unwrap_or(config::MIN_VOLUME_DB)
+                );
+                player.set_max_volume(v);
+                // Let the parent hear the new maximum.
+                player.set_volume(v);
+                // A parent expects the limit to hold even if the power is cut later on.
+                save_state.set_max_volume(player.max_volume());
+                save_state.set_volume(player.volume());
+                log_err!(
+                    "Failed to write save state",
+                    save_state.save(&save_state_path)
+                );
+                player.play_earcon(Earcon::Confirm);
+            }
+            Ok(Event::SleepTimer(d)) => {
+                led_cmd_sink
+                    .send(led::LedCommand::Blink(Duration::from_millis(500)))
+                    .unwrap();
+                if d == Duration::from_secs(0) {
+                    log!("Sleep timer cancelled");
+                    sleep_deadline = None;
+                } else {
+                    log!("Shutting down in {:?}", d);
+                    sleep_deadline = Some(Instant::now() + d);
+                }
+            }
+            Ok(Event::Next) => {
+                led_cmd_sink
+                    .send(led::LedCommand::Blink(Duration::from_millis(500)))
+                    .unwrap();
+                player.skip();
+            }
+            Ok(Event::NextChapter) => {
+                led_cmd_sink
+                    .send(led::LedCommand::Blink(Duration::from_millis(500)))
+                    .unwrap();
+                log_err!("Jump to next chapter", player.next_chapter());
+                log_chapter(&player);
+            }
+            Ok(Event::PreviousChapter) => {
+                led_cmd_sink
+                    .send(led::LedCommand::Blink(Duration::from_millis(500)))
+                    .unwrap();
+                log_err!("Jump to previous chapter", player.previous_chapter());
+                log_chapter(&player);
+            }
+            Ok(Event::Shuffle) => {
+                led_cmd_sink
+                    .send(led::LedCommand::Blink(Duration::from_millis(500)))
+                    .unwrap();
+                log_err!("Shuffle playlist", player.shuffle(shuffle_seed()));
+            }
+            Ok(Event::ToggleLock) => {
+                locked = !locked;
+                log!("Controls {}", if locked { "locked" } else { "unlocked" });
+                led_cmd_sink
+                    .send(led::LedCommand::Flash(
+                        if locked { 2 } else { 1 },
+                        Duration::from_millis(100),
+                    ))
+                    .unwrap();
+                player.play_earcon(if locked {
+                    Earcon::Confirm
+                } else {
+                    Earcon::Cancel
+                });
+            }
+            Ok(Event::ToggleLearnMode) => {
+                learn_mode = !learn_mode;
+                log!("Learn mode {}", if learn_mode { "on" } else { "off" });
+                led_cmd_sink
+                    .send(led::LedCommand::Flash(
+                        if learn_mode { 3 } else { 1 },
+                        Duration::from_millis(100),
+                    ))
+                    .unwrap();
+                player.play_earcon(if learn_mode {
+                    Earcon::Confirm
+                } else {
+                    Earcon::Cancel
+                });
             }
             Ok(Event::Play(uid)) => {
+                if learn_mode && !file_map.contains_key(&uid) {
+                    learn_mode = false;
+                    match learn::learn_card(uid, &media_definition_path, data_root, &mut file_map) {
+                        Ok(Some(path)) => {
+                            log!("Assigned card {} to {:?}", uid, path);
+                            led_cmd_sink
+                                .send(led::LedCommand::Flash(3, Duration::from_millis(100)))
+                                .unwrap();
+                            player.play_earcon(Earcon::Confirm);
+                        }
+                        Ok(None) => {
+                            log!("No unassigned media left in the inbox");
+                            player.play_earcon(Earcon::Error);
+                        }
+                        Err(e) => {
+                            log!("Failed to assign card {}: {}", uid, e);
+                            player.play_earcon(Earcon::Error);
+                        }
+                    }
+                }
                 let (old_uid, remove_time) = match card_state {
                     CardState::Previous(old_uid, remove_time) => (Some(old_uid), Some(remove_time)),
                     CardState::Current(old_uid) => (Some(old_uid), None),
                     CardState::Nothing => (None, None),
                 };
-                if old_uid == Some(uid) && !player.idle() {
+                let resume = file_map.get(&uid).map(|c| c.options.resume).unwrap_or(true);
+                if old_uid == Some(uid) && !player.idle() && resume {
                     if let Some(remove_time) = remove_time {
-                        let stop_time = SystemTime::now()
-                            .duration_since(remove_time)
-                            .unwrap_or(Duration::from_millis(0));
-                        log_err!(
-                            "Rewind from remove time",
-                            player.rewind(resume_rewind_time(stop_time))
-                        );
+                        rewind_for_resume(&mut player, remove_time);
                     }
                     player.play();
+                    card_state = CardState::Current(uid);
                 } else {
-                    if let Some(file) = file_map.get(&uid) {
-                        log!("Starting to play {:?}", file);
-                        log_err!("Load file for card", player.load_file(file, None));
-                        player.play();
+                    if let Some(old_uid) = old_uid.filter(|u| file_map.contains_key(u)) {
+                        store_resume_point(
+                            &mut save_state,
+                            &player,
+                            old_uid,
+                            remove_time.unwrap_or_else(SystemTime::now),
+                        );
+                    }
+                    if let Some(card) = file_map.get(&uid) {
+                        log!("Starting to play {}", card.name());
+                        let resume_point = save_state
+                            .resume_point(uid)
+                            .map(|(track, pos, stop_time)| (track, resume_pos(pos, stop_time)));
+                        // The previous media stays loaded if the card is broken, so that it can
+                        // still be resumed.
+                        if load_card(&mut player, card, resume_point) {
+                            player.play();
+                            card_state = CardState::Current(uid);
+                        }
                     } else {
+                        // The previous media stays loaded, and its position still belongs to the previous card.
                         log!("Unkown card: {}", uid);
                     }
                 }
-                card_state = CardState::Current(uid);
                 led_cmd_sink
                     .send(led::LedCommand::Blink(Duration::from_millis(500)))
                     .unwrap();
             }
+            Ok(Event::Stop) if command_card_present => {
+                command_card_present = false;
+            }
             Ok(Event::Stop) => {
                 led_cmd_sink
                     .send(led::LedCommand::DoubleBlink(
@@ -309,6 +675,16 @@ fn run(options: Options) {
                 }
                 player.pause();
             }
+            Ok(Event::MediaDefinitionChanged(media, new_filters)) => {
+                log!("Loaded new media definition with {} cards", media.len());
+                file_map = media;
+                // Replacing the filters resets their state, which might be audible.
+                if new_filters != filters {
+                    log!("Output filters changed");
+                    player.set_filters(build_filters(new_filters.as_deref()));
+                    filters = new_filters;
+                }
+            }
             Ok(Event::Shutdown) => {
                 player.pause();
                 stopped = true;
@@ -318,6 +694,13 @@ fn run(options: Options) {
                 panic!("Player event channel closed unexpectedly")
             }
         }
+        if sleep_deadline.map(|d| Instant::now() >= d).unwrap_or(false) {
+            log!("Sleep timer expired");
+            sleep_deadline = None;
+            sleep_timer_expired = true;
+            player.pause();
+            stopped = true;
+        }
         if player.playing() {
             silence_begin = None;
         } else {
@@ -333,7 +716,9 @@ fn run(options: Options) {
         player.push_samples();
     }
 
-    if silence_begin.is_none() || silence_begin.unwrap().elapsed() < config::IDLE_SLEEP_TIME {
+    if !sleep_timer_expired
+        && (silence_begin.is_none() || silence_begin.unwrap().elapsed() < config::IDLE_SLEEP_TIME)
+    {
         // Only blink led if not turned off automatically. We don't want to wake anyone up if they
         // actually went asleep.
         led_cmd_sink
@@ -345,13 +730,27 @@ fn run(options: Options) {
             .unwrap();
     }
 
-    let playback_pos = match (card_state, player.playback_pos()) {
-        (CardState::Previous(uid, remove_time), Some(pos)) => Some((uid, pos, remove_time)),
-        (CardState::Current(uid), Some(pos)) => Some((uid, pos, SystemTime::now())),
+    let playback_pos = match (card_state, player.track(), player.playback_pos()) {
+        (CardState::Previous(uid, remove_time), Some(track), Some(pos)) => {
+            Some((uid, track, pos, remove_time))
+        }
+        (CardState::Current(uid), Some(track), Some(pos)) => {
+            Some((uid, track, pos, SystemTime::now()))
+        }
         _ => None,
     };
+    // The position belongs to the media of a known card. Otherwise it would be resumed in different
+    // media once the card is assigned.
+    let playback_pos = playback_pos.filter(|(uid, ..)| file_map.contains_key(uid));
     save_state.set_playback_state(playback_pos);
-    save_state.set_volume(*player.volume());
+    save_state.set_volume(player.volume());
+    save_state.set_max_volume(player.max_volume());
+    let (decoder_underruns, output_underruns) = player.underruns();
+    log!(
+        "Underruns: {} (decoder), {} (output)",
+        decoder_underruns,
+        output_underruns
+    );
     log_err!(
         "Failed to write save state",
         save_state.save(&save_state_path)
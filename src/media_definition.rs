@@ -1,7 +1,241 @@
+use crate::fade::FadeCurve;
+use crate::filter::FilterSpec;
+use crate::player::Volume;
 use crate::rfid::Uid;
 use std::collections::HashMap;
 use std::io::{BufRead, BufReader};
 use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Per-card settings that can be specified in a card section of the media definition file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CardOptions {
+    pub title: Option<String>,
+    /// Added to the current volume (in dB) while the card is playing.
+    pub volume_offset: f32,
+    /// Continue where we stopped last time. Otherwise the card always starts from the beginning.
+    pub resume: bool,
+    /// Start over with the first track after the last one has finished.
+    pub looping: bool,
+    /// Play tracks in random order. Since the order changes every time, this implies !resume.
+    pub shuffle: bool,
+    /// Position in the first track where playback starts (if not resuming).
+    pub start_offset: Duration,
+    /// Fade in/out duration to use instead of the default.
+    pub fade: Option<Duration>,
+    /// Fade curve to use instead of the default.
+    pub fade_curve: Option<FadeCurve>,
+}
+
+impl Default for CardOptions {
+    fn default() -> Self {
+        CardOptions {
+            title: None,
+            volume_offset: 0.0,
+            resume: true,
+            looping: false,
+            shuffle: false,
+            start_offset: Duration::from_secs(0),
+            fade: None,
+            fade_curve: None,
+        }
+    }
+}
+
+/// Actions that can be triggered by a card instead of playing media.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    /// Assign the next unknown card to the next unassigned media in the inbox.
+    Learn,
+    Shutdown,
+    /// Shut down after the given time. A duration of zero cancels the timer.
+    SleepTimer(Duration),
+    /// Set the volume to the given level.
+    Volume(Volume),
+    /// Set the level that the volume cannot be turned up beyond.
+    MaxVolume(Volume),
+    /// Skip to the next track.
+    Next,
+    /// Jump to the next chapter of the current track (or the next track).
+    NextChapter,
+    /// Jump to the start of the current (or previous) chapter.
+    PreviousChapter,
+    /// Play the current playlist in random order.
+    Shuffle,
+    /// Toggle ignoring all cards (except for this one) and the volume control.
+    Lock,
+}
+
+impl Command {
+    /// Parse a command (without the leading '@') and its argument, e.g., "sleep-timer 30m".
+    fn parse(s: &str) -> Option<Self> {
+        let end = s.find(char::is_whitespace).unwrap_or(s.len());
+        let arg = s[end..].trim();
+        match (&s[..end], arg) {
+            ("learn", "") => Some(Command::Learn),
+            ("shutdown", "") => Some(Command::Shutdown),
+            ("sleep-timer", arg) => parse_duration(arg).map(Command::SleepTimer),
+            ("volume", arg) => match parse_db(arg) {
+                Some(db) => Some(Command::Volume(Volume::from_db(db))),
+                None => arg
+                    .parse()
+                    .ok()
+                    .filter(|v| *v <= crate::player::LEGACY_MAX_VOLUME)
+                    .map(|v| Command::Volume(Volume::from_legacy(v))),
+            },
+            ("max-volume", arg) => match parse_db(arg) {
+                Some(db) => Some(Command::MaxVolume(Volume::from_db(db))),
+                None => arg
+                    .parse()
+                    .ok()
+                    .filter(|v| *v > 0 && *v <= crate::player::LEGACY_MAX_VOLUME)
+                    .map(|v| Command::MaxVolume(Volume::from_legacy(v))),
+            },
+            ("next", "") => Some(Command::Next),
+            ("next-chapter", "") => Some(Command::NextChapter),
+            ("previous-chapter", "") => Some(Command::PreviousChapter),
+            ("shuffle", "") => Some(Command::Shuffle),
+            ("lock", "") => Some(Command::Lock),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Command::Learn => write!(f, "@learn"),
+            Command::Shutdown => write!(f, "@shutdown"),
+            Command::SleepTimer(d) if d.subsec_millis() == 0 => {
+                write!(f, "@sleep-timer {}s", d.as_secs())
+            }
+            Command::SleepTimer(d) => write!(f, "@sleep-timer {}ms", d.as_millis()),
+            Command::Volume(v) => match v.db() {
+                Some(db) => write!(f, "@volume {}dB", db),
+                None => write!(f, "@volume 0"),
+            },
+            // The maximum volume is always audible.
+            Command::MaxVolume(v) => write!(
+                f,
+                "@max-volume {}dB",
+                v.db().unwrap_or(crate::config::MIN_VOLUME_DB)
+            ),
+            Command::Next => write!(f, "@next"),
+            Command::NextChapter => write!(f, "@next-chapter"),
+            Command::PreviousChapter => write!(f, "@previous-chapter"),
+            Command::Shuffle => write!(f, "@shuffle"),
+            Command::Lock => write!(f, "@lock"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Target {
+    Media(PathBuf),
+    Command(Command),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CardDefinition {
+    pub target: Target,
+    pub options: CardOptions,
+}
+
+impl CardDefinition {
+    /// Name of the card for log messages.
+    pub fn name(&self) -> String {
+        match (&self.options.title, &self.target) {
+            (Some(title), _) => title.clone(),
+            (None, Target::Media(path)) => path.to_string_lossy().into_owned(),
+            (None, Target::Command(command)) => command.to_string(),
+        }
+    }
+
+    pub fn media_path(&self) -> Option<&Path> {
+        match self.target {
+            Target::Media(ref path) => Some(path),
+            Target::Command(_) => None,
+        }
+    }
+
+    pub fn command(&self) -> Option<&Command> {
+        match self.target {
+            Target::Media(_) => None,
+            Target::Command(ref command) => Some(command),
+        }
+    }
+}
+
+pub type MediaDefinition = HashMap<Uid, CardDefinition>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Problem {
+    Unreadable,
+    BadUid(String),
+    MissingPath,
+    BadCommand(String),
+    BadOption(String),
+    DuplicateUid(Uid, usize),
+    PathNotFound(PathBuf),
+    OutsideDataRoot(PathBuf),
+}
+
+impl Problem {
+    /// Errors mean that (parts of) the file could not be understood, as opposed to problems with
+    /// the referenced media files.
+    pub fn is_error(&self) -> bool {
+        !matches!(self, Problem::PathNotFound(_) | Problem::OutsideDataRoot(_))
+    }
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Problem::Unreadable => write!(f, "Line cannot be read (not utf8?)"),
+            Problem::BadUid(s) => write!(f, "Invalid card uid '{}'", s),
+            Problem::MissingPath => write!(f, "No media path for card"),
+            Problem::BadCommand(s) => write!(f, "Invalid command '{}'", s),
+            Problem::BadOption(s) => write!(f, "Invalid option '{}'", s),
+            Problem::DuplicateUid(uid, first) => write!(
+                f,
+                "Card {} is already defined in line {}, ignoring this one",
+                uid, first
+            ),
+            Problem::PathNotFound(p) => write!(f, "Media path {:?} does not exist", p),
+            Problem::OutsideDataRoot(p) => {
+                write!(f, "Media path {:?} is outside of the data directory", p)
+            }
+        }
+    }
+}
+
+/// A problem in the media definition file. Lines start at 1.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub line: usize,
+    pub problem: Problem,
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.problem)
+    }
+}
+
+pub struct ParseResult {
+    pub media: MediaDefinition,
+    /// Filters of the [output] section (in order), or None if there is no such section
+    pub filters: Option<Vec<FilterSpec>>,
+    pub diagnostics: Vec<Diagnostic>,
+    /// Line in which each card is defined
+    lines: HashMap<Uid, usize>,
+}
+
+impl ParseResult {
+    pub fn has_errors(&self) -> bool {
+        self.diagnostics.iter().any(|d| d.problem.is_error())
+    }
+}
 
 fn parse_num(s: &str) -> Option<u32> {
     match s.as_bytes() {
@@ -12,46 +246,417 @@ fn parse_num(s: &str) -> Option<u32> {
     }
     .ok()
 }
-fn parse_line(l: &str) -> Option<(Uid, PathBuf)> {
-    let end = l.find(" ")?;
+/// Uids are written in colon separated hex form ("04:A2:1B:3C:5D:6E:7F"). For compatibility,
+/// 4 byte uids can still be written as a single number.
+fn parse_uid(s: &str) -> Option<Uid> {
+    if s.contains(':') {
+        Uid::parse(s)
+    } else {
+        parse_num(s).map(Uid::from_legacy)
+    }
+}
+fn parse_line(l: &str) -> Result<(Uid, &str), Problem> {
+    let end = l.find(char::is_whitespace).unwrap_or(l.len());
     let uid_str = &l[..end];
-    let path_str = l[end..].trim();
-    let uid = Uid(parse_num(uid_str)?);
-    let path = PathBuf::from(path_str);
-    Some((uid, path))
+    let target_str = l[end..].trim();
+    let uid = parse_uid(uid_str).ok_or_else(|| Problem::BadUid(uid_str.to_owned()))?;
+    if target_str.is_empty() {
+        return Err(Problem::MissingPath);
+    }
+    Ok((uid, target_str))
+}
+
+/// Targets starting with '@' are commands, everything else is a path relative to the media root.
+fn parse_target(s: &str, media_file_root: &Path) -> Result<Target, Problem> {
+    if let Some(command) = s.strip_prefix('@') {
+        Command::parse(command)
+            .map(Target::Command)
+            .ok_or_else(|| Problem::BadCommand(s.to_owned()))
+    } else {
+        Ok(Target::Media(media_file_root.join(s)))
+    }
+}
+
+fn parse_bool(s: &str) -> Option<bool> {
+    match s {
+        "true" | "yes" | "on" | "1" => Some(true),
+        "false" | "no" | "off" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+/// Parse durations like "90", "90s", "1m30s", "1h" or "500ms". Plain numbers are seconds.
+pub fn parse_duration(s: &str) -> Option<Duration> {
+    if s.is_empty() {
+        return None;
+    }
+    if let Ok(secs) = s.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let mut total = Duration::from_secs(0);
+    let mut rest = s;
+    while !rest.is_empty() {
+        let num_end = rest.find(|c: char| !c.is_ascii_digit())?;
+        if num_end == 0 {
+            return None;
+        }
+        let num = rest[..num_end].parse::<u64>().ok()?;
+        rest = &rest[num_end..];
+        let unit_end = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        total += match &rest[..unit_end] {
+            "h" => Duration::from_secs(num * 60 * 60),
+            "m" => Duration::from_secs(num * 60),
+            "s" => Duration::from_secs(num),
+            "ms" => Duration::from_millis(num),
+            _ => return None,
+        };
+        rest = &rest[unit_end..];
+    }
+    Some(total)
+}
+
+/// Parse a gain like "-4dB" or "+3.5 dB".
+fn parse_db(s: &str) -> Option<f32> {
+    let num = s.strip_suffix("dB")?.trim_end();
+    num.trim_start_matches('+').parse().ok()
+}
+
+/// Parse a volume offset. Plain numbers are volume steps of older versions, which were 6 dB
+/// apart.
+fn parse_volume_offset(s: &str) -> Option<f32> {
+    parse_db(s).or_else(|| {
+        let steps: i8 = s.trim_start_matches('+').parse().ok()?;
+        Some(steps as f32 * crate::player::LEGACY_VOLUME_STEP_DB)
+    })
+}
+
+/// Parse a "[<uid>]" section header.
+fn parse_section_header(l: &str) -> Result<Uid, Problem> {
+    let uid_str = l.trim_start_matches('[').trim_end_matches(']').trim();
+    if l.ends_with(']') {
+        parse_uid(uid_str).ok_or_else(|| Problem::BadUid(uid_str.to_owned()))
+    } else {
+        Err(Problem::BadUid(l.to_owned()))
+    }
+}
+
+/// Parse a "key = value" line of a card section.
+fn parse_option_line(l: &str) -> Option<(&str, &str)> {
+    let eq = l.find('=')?;
+    let key = l[..eq].trim();
+    if key.is_empty() || key.contains(char::is_whitespace) {
+        // Probably a legacy line with a '=' in the path
+        return None;
+    }
+    Some((key, l[eq + 1..].trim()))
+}
+
+/// Apply an option to the card section. Returns None if the key or value is invalid.
+fn apply_option(section: &mut Section, key: &str, value: &str) -> Option<()> {
+    let options = &mut section.options;
+    match key {
+        "path" => section.target = Some(value.to_owned()),
+        "title" => options.title = Some(value.to_owned()),
+        "volume" => options.volume_offset = parse_volume_offset(value)?,
+        "resume" => options.resume = parse_bool(value)?,
+        "loop" => options.looping = parse_bool(value)?,
+        "shuffle" => options.shuffle = parse_bool(value)?,
+        "start" => options.start_offset = parse_duration(value)?,
+        "fade" => options.fade = Some(parse_duration(value)?),
+        "fade-curve" => options.fade_curve = Some(FadeCurve::parse(value)?),
+        _ => return None,
+    }
+    Some(())
+}
+
+/// Parse the arguments of an output filter, e.g., "peaking = 1000 -3 1.4" (frequency in Hz,
+/// gain in dB and q). Returns None if the key or value is invalid.
+fn parse_filter(key: &str, value: &str) -> Option<FilterSpec> {
+    let args = value
+        .split_whitespace()
+        .map(|a| a.trim_start_matches('+').parse::<f32>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    let frequency = |f: f32| Some(f).filter(|f| *f > 0.0 && *f <= MAX_FILTER_FREQUENCY);
+    Some(match (key, args.as_slice()) {
+        ("high-pass", &[f]) => FilterSpec::HighPass {
+            frequency: frequency(f)?,
+        },
+        ("low-shelf", &[f, gain_db]) => FilterSpec::LowShelf {
+            frequency: frequency(f)?,
+            gain_db,
+        },
+        ("high-shelf", &[f, gain_db]) => FilterSpec::HighShelf {
+            frequency: frequency(f)?,
+            gain_db,
+        },
+        ("peaking", &[f, gain_db, q]) if q > 0.0 => FilterSpec::Peaking {
+            frequency: frequency(f)?,
+            gain_db,
+            q,
+        },
+        ("balance", &[b]) if (-1.0..=1.0).contains(&b) => FilterSpec::Balance(b),
+        _ => return None,
+    })
+}
+
+/// Filters need to stay below half of the lowest sample rate that we might output.
+const MAX_FILTER_FREQUENCY: f32 = 20000.0;
+/// Header of the section that configures the output filters (instead of a card).
+const OUTPUT_SECTION: &str = "[output]";
+
+struct Section {
+    line: usize,
+    /// None if the header is invalid. We still consume the options of the section in that case.
+    uid: Option<Uid>,
+    target: Option<String>,
+    options: CardOptions,
+}
+
+impl Section {
+    fn new(line: usize, uid: Option<Uid>) -> Self {
+        Section {
+            line,
+            uid,
+            target: None,
+            options: CardOptions::default(),
+        }
+    }
+}
+
+struct Parser<'a> {
+    media_file_root: &'a Path,
+    result: ParseResult,
+}
+
+impl Parser<'_> {
+    fn report(&mut self, line: usize, problem: Problem) {
+        self.result.diagnostics.push(Diagnostic { line, problem });
+    }
+
+    fn insert(&mut self, line: usize, uid: Uid, target: &str, options: CardOptions) {
+        if let Some(first) = self.result.lines.get(&uid) {
+            self.report(line, Problem::DuplicateUid(uid, *first));
+            return;
+        }
+        let target = match parse_target(target, self.media_file_root) {
+            Ok(target) => target,
+            Err(problem) => return self.report(line, problem),
+        };
+        self.result.lines.insert(uid, line);
+        self.result
+            .media
+            .insert(uid, CardDefinition { target, options });
+    }
+
+    fn finish_section(&mut self, section: Option<Section>) {
+        match section {
+            Some(Section {
+                line,
+                uid: Some(uid),
+                target: Some(target),
+                options,
+            }) => self.insert(line, uid, &target, options),
+            Some(Section {
+                line,
+                uid: Some(_),
+                target: None,
+                ..
+            }) => self.report(line, Problem::MissingPath),
+            _ => {}
+        }
+    }
 }
 
 pub fn load_media_definition(
     map_definition_file: impl AsRef<Path>,
     media_file_root: impl AsRef<Path>,
-) -> HashMap<Uid, PathBuf> {
-    let f = std::fs::File::open(map_definition_file).unwrap(); // If this fails we cannot do anything anyways.
-    parse_media_definition(f, media_file_root)
+) -> std::io::Result<ParseResult> {
+    let f = std::fs::File::open(map_definition_file)?;
+    let mut result = parse_media_definition(f, &media_file_root);
+    check_paths(&mut result, media_file_root);
+    Ok(result)
+}
+
+/// Append a mapping from the card to the target (path relative to the media root or command).
+/// Since we only ever append a single line, the existing definitions are never damaged.
+pub fn append_card(
+    map_definition_file: impl AsRef<Path>,
+    uid: Uid,
+    target: impl std::fmt::Display,
+) -> std::io::Result<()> {
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    let mut f = std::fs::OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(map_definition_file)?;
+
+    // Make sure that we start on a new line.
+    let mut needs_newline = false;
+    if f.seek(SeekFrom::End(0))? > 0 {
+        let mut last = [0u8];
+        f.seek(SeekFrom::End(-1))?;
+        f.read_exact(&mut last)?;
+        needs_newline = last[0] != b'\n';
+    }
+    let line = format!(
+        "{}{} {}\n",
+        if needs_newline { "\n" } else { "" },
+        uid,
+        target
+    );
+    f.write_all(line.as_bytes())?;
+    f.sync_all()
 }
 
+/// Report media paths that do not exist or that point outside of the media file root.
+pub fn check_paths(result: &mut ParseResult, media_file_root: impl AsRef<Path>) {
+    let root = media_file_root.as_ref();
+    let root = root.canonicalize().unwrap_or_else(|_| root.to_owned());
+
+    let mut problems = Vec::new();
+    for (uid, card) in &result.media {
+        let path = match card.media_path() {
+            Some(path) => path,
+            None => continue,
+        };
+        let problem = match path.canonicalize() {
+            Ok(p) if !p.starts_with(&root) => Problem::OutsideDataRoot(path.to_owned()),
+            Ok(_) => continue,
+            Err(_) => Problem::PathNotFound(path.to_owned()),
+        };
+        problems.push((*uid, problem));
+    }
+    for (uid, problem) in problems {
+        let line = result.lines[&uid];
+        result.diagnostics.push(Diagnostic { line, problem });
+    }
+    result.diagnostics.sort_by_key(|d| d.line);
+}
+
+/// The media definition consists of lines that map a card uid to a path:
+///
+/// ```text
+/// 04:A2:1B:3C:5D:6E:7F audiobooks/momo
+/// ```
+///
+/// and/or sections that additionally specify options for the card:
+///
+/// ```text
+/// [04:A2:1B:3C:5D:6E:7F]
+/// path = audiobooks/momo
+/// title = Momo
+/// volume = -4dB
+/// resume = true
+/// loop = false
+/// shuffle = false
+/// start = 1m30s
+/// fade = 2s
+/// fade-curve = equal-power
+/// ```
+///
+/// The volume offset and the levels of `@volume` and `@max-volume` are given in dB. Older versions
+/// used volume steps instead, which were 6 dB apart. Plain numbers are still read that way, so
+/// `volume = -2` means -12 dB and `@volume 13` means -12 dB as well (`@volume 0` is silent).
+///
+/// The fade curve is one of `linear`, `equal-power` or `logarithmic`.
+///
+/// A single `[output]` section configures the filters that process the output, in the given
+/// order. Frequencies are in Hz, gains in dB. Without the section, the default filters of the
+/// config are used, so an empty section turns them off:
+///
+/// ```text
+/// [output]
+/// high-pass = 150
+/// low-shelf = 200 +3
+/// peaking = 2500 -4 1.4
+/// high-shelf = 8000 -2
+/// balance = -0.1
+/// ```
+///
+/// Instead of a path, a card can also trigger a command: `@learn`, `@shutdown`,
+/// `@sleep-timer 30m`, `@volume -20dB`, `@max-volume -6dB`, `@next`, `@next-chapter`,
+/// `@previous-chapter`, `@shuffle` or `@lock`.
 pub fn parse_media_definition(
     src: impl std::io::Read,
     media_file_root: impl AsRef<Path>,
-) -> HashMap<Uid, PathBuf> {
+) -> ParseResult {
     let f = BufReader::new(src);
-    let media_file_root = media_file_root.as_ref();
-
-    let mut map = HashMap::new();
+    let mut parser = Parser {
+        media_file_root: media_file_root.as_ref(),
+        result: ParseResult {
+            media: HashMap::new(),
+            filters: None,
+            diagnostics: Vec::new(),
+            lines: HashMap::new(),
+        },
+    };
+    let mut section: Option<Section> = None;
+    let mut in_output_section = false;
 
-    for l in f.lines() {
+    for (i, l) in f.lines().enumerate() {
+        let line = i + 1;
         let l = match l {
             Ok(l) => l,
-            Err(_) => continue,
+            Err(_) => {
+                parser.report(line, Problem::Unreadable);
+                continue;
+            }
         };
-        let l = l.trim_start();
+        let l = l.trim();
         if l.is_empty() || l.starts_with("#") {
             continue;
         }
-        if let Some((uid, path)) = parse_line(l) {
-            map.insert(uid, media_file_root.join(path));
+        if l.starts_with('[') {
+            parser.finish_section(section.take());
+            in_output_section = l == OUTPUT_SECTION;
+            if in_output_section {
+                parser.result.filters.get_or_insert_with(Vec::new);
+                continue;
+            }
+            let uid = match parse_section_header(l) {
+                Ok(uid) => Some(uid),
+                Err(problem) => {
+                    parser.report(line, problem);
+                    None
+                }
+            };
+            section = Some(Section::new(line, uid));
+            continue;
+        }
+        if in_output_section {
+            if let Some((key, value)) = parse_option_line(l) {
+                match parse_filter(key, value) {
+                    Some(filter) => parser
+                        .result
+                        .filters
+                        .get_or_insert_with(Vec::new)
+                        .push(filter),
+                    None => parser.report(line, Problem::BadOption(l.to_owned())),
+                }
+                continue;
+            }
+            in_output_section = false;
+        }
+        if let Some(ref mut s) = section {
+            if let Some((key, value)) = parse_option_line(l) {
+                if apply_option(s, key, value).is_none() {
+                    parser.report(line, Problem::BadOption(l.to_owned()));
+                }
+                continue;
+            }
+        }
+        parser.finish_section(section.take());
+        match parse_line(l) {
+            Ok((uid, target)) => parser.insert(line, uid, target, CardOptions::default()),
+            Err(problem) => parser.report(line, problem),
         }
     }
-    map
+    parser.finish_section(section.take());
+    parser.result
 }
 
 #[cfg(test)]
@@ -69,17 +674,23 @@ mod test {
 
     #[test]
     fn test_parse_line() {
-        assert_eq!(parse_line(""), None);
-        assert_eq!(parse_line(" "), None);
-        assert_eq!(parse_line("bla"), None);
-        assert_eq!(parse_line("123"), None);
+        assert_eq!(parse_line("").ok(), None);
+        assert_eq!(parse_line(" ").ok(), None);
+        assert_eq!(parse_line("bla"), Err(Problem::BadUid("bla".to_owned())));
+        assert_eq!(parse_line("123"), Err(Problem::MissingPath));
+        assert_eq!(parse_line("123   "), Err(Problem::MissingPath));
         assert_eq!(
             parse_line("123 /foo/bar"),
-            Some((Uid(123), PathBuf::from("/foo/bar")))
+            Ok((Uid::from_legacy(123), "/foo/bar"))
+        );
+        assert_eq!(parse_line("0x42 baz"), Ok((Uid::from_legacy(0x42), "baz")));
+        assert_eq!(
+            parse_line("04:A2:1B:3C:5D:6E:7F audiobooks/"),
+            Ok((Uid::parse("04:a2:1b:3c:5d:6e:7f").unwrap(), "audiobooks/"))
         );
         assert_eq!(
-            parse_line("0x42 baz"),
-            Some((Uid(0x42), PathBuf::from("baz")))
+            parse_line("04:A2:1B foo"),
+            Err(Problem::BadUid("04:A2:1B".to_owned()))
         );
     }
 
@@ -94,15 +705,370 @@ mod test {
 
             # just some comment
             0xcafe cafe.ogg
+            01:02:03:04:05:06:07 album
             "[..],
         );
-        let m = parse_media_definition(f, "/root/");
-        assert_eq!(m.len(), 3);
-        assert_eq!(m.get(&Uid(0x123)).unwrap(), &PathBuf::from("/root/foo/bar"));
-        assert_eq!(m.get(&Uid(456)).unwrap(), &PathBuf::from("/bla"));
+        let r = parse_media_definition(f, "/root/");
+        assert!(r.diagnostics.is_empty());
+        let m = r.media;
+        assert_eq!(m.len(), 4);
+        assert_eq!(
+            m.get(&Uid::from_legacy(0x123)).unwrap().target,
+            Target::Media(PathBuf::from("/root/foo/bar"))
+        );
+        assert_eq!(
+            m.get(&Uid::from_legacy(456)).unwrap().target,
+            Target::Media(PathBuf::from("/bla"))
+        );
+        assert_eq!(
+            m.get(&Uid::from_legacy(0xcafe)).unwrap().target,
+            Target::Media(PathBuf::from("/root/cafe.ogg"))
+        );
+        assert_eq!(
+            m.get(&Uid::new(&[1, 2, 3, 4, 5, 6, 7]).unwrap())
+                .unwrap()
+                .target,
+            Target::Media(PathBuf::from("/root/album"))
+        );
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("90s"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("1m30s"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("1h"), Some(Duration::from_secs(3600)));
+        assert_eq!(parse_duration("500ms"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("m"), None);
+        assert_eq!(parse_duration("5x"), None);
+        assert_eq!(parse_duration("-5"), None);
+    }
+
+    #[test]
+    fn test_parse_volume_offset() {
+        assert_eq!(parse_volume_offset("-4dB"), Some(-4.0));
+        assert_eq!(parse_volume_offset("+2.5 dB"), Some(2.5));
+        assert_eq!(parse_volume_offset("0"), Some(0.0));
+        // Legacy volume steps
+        assert_eq!(parse_volume_offset("-2"), Some(-12.04));
+        assert_eq!(parse_volume_offset("-2.5"), None);
+        assert_eq!(parse_volume_offset("dB"), None);
+        assert_eq!(parse_volume_offset("loud"), None);
+    }
+
+    #[test]
+    fn test_parse_sections() {
+        let f = std::io::Cursor::new(
+            r"
+            0x123 legacy.ogg
+
+            [04:A2:1B:3C:5D:6E:7F]
+            path = audiobooks/momo
+            title = Momo and the time thieves
+            volume = -4dB
+            start = 1m
+
+            [0xcafe]
+            # comment in a section
+            path=music
+            resume = false
+            loop = yes
+            shuffle = on
+            volume = +3
+            fade = 2s
+            fade-curve = logarithmic
+            0x456 weird = name.ogg
+
+            [0x789]
+            title = Has no path
+            ",
+        );
+        let r = parse_media_definition(f, "/root/");
+        let m = r.media;
+        assert_eq!(m.len(), 4);
+        assert_eq!(
+            r.diagnostics,
+            vec![Diagnostic {
+                line: 21,
+                problem: Problem::MissingPath
+            }]
+        );
+
+        let legacy = m.get(&Uid::from_legacy(0x123)).unwrap();
+        assert_eq!(
+            legacy.media_path(),
+            Some(PathBuf::from("/root/legacy.ogg").as_path())
+        );
+        assert_eq!(legacy.options, CardOptions::default());
+
+        let momo = m.get(&Uid::parse("04:A2:1B:3C:5D:6E:7F").unwrap()).unwrap();
+        assert_eq!(
+            momo.media_path(),
+            Some(PathBuf::from("/root/audiobooks/momo").as_path())
+        );
+        assert_eq!(
+            momo.options,
+            CardOptions {
+                title: Some("Momo and the time thieves".to_owned()),
+                volume_offset: -4.0,
+                start_offset: Duration::from_secs(60),
+                ..CardOptions::default()
+            }
+        );
+        assert_eq!(momo.name(), "Momo and the time thieves");
+
+        let music = m.get(&Uid::from_legacy(0xcafe)).unwrap();
+        assert_eq!(
+            music.media_path(),
+            Some(PathBuf::from("/root/music").as_path())
+        );
+        assert_eq!(
+            music.options,
+            CardOptions {
+                volume_offset: 3.0 * crate::player::LEGACY_VOLUME_STEP_DB,
+                resume: false,
+                looping: true,
+                shuffle: true,
+                fade: Some(Duration::from_secs(2)),
+                fade_curve: Some(FadeCurve::Logarithmic),
+                ..CardOptions::default()
+            }
+        );
+
+        let weird = m.get(&Uid::from_legacy(0x456)).unwrap();
+        assert_eq!(
+            weird.media_path(),
+            Some(PathBuf::from("/root/weird = name.ogg").as_path())
+        );
+
+        assert!(!m.contains_key(&Uid::from_legacy(0x789)));
+    }
+
+    #[test]
+    fn test_output_section() {
+        let f = std::io::Cursor::new(
+            r"0x1 foo
+            [output]
+            high-pass = 120
+            peaking = 2500 -4 1.4
+            low-shelf = 200 +3
+            balance = 2
+            high-shelf = 8000
+            0x2 bar
+            ",
+        );
+        let r = parse_media_definition(f, "/root/");
+        assert_eq!(r.media.len(), 2);
+        assert_eq!(
+            r.filters,
+            Some(vec![
+                FilterSpec::HighPass { frequency: 120.0 },
+                FilterSpec::Peaking {
+                    frequency: 2500.0,
+                    gain_db: -4.0,
+                    q: 1.4
+                },
+                FilterSpec::LowShelf {
+                    frequency: 200.0,
+                    gain_db: 3.0
+                },
+            ])
+        );
+        let lines = r.diagnostics.iter().map(|d| d.line).collect::<Vec<_>>();
+        assert_eq!(lines, vec![6, 7]);
+
+        let r = parse_media_definition(std::io::Cursor::new("0x1 foo"), "/root/");
+        assert_eq!(r.filters, None);
+        let r = parse_media_definition(std::io::Cursor::new("[output]\n0x1 foo"), "/root/");
+        assert_eq!(r.filters, Some(Vec::new()));
+        assert_eq!(r.media.len(), 1);
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let f = std::io::Cursor::new(
+            r"0x123 foo
+            bla foo
+            0x456
+            0x123 bar
+
+            [0x789]
+            path = baz
+            volume = loud
+            colour = red
+            [0xzz]
+            path = ignored
+            [0xabc
+            0x1 @learn
+            0x2 @unknown
+            ",
+        );
+        let r = parse_media_definition(f, "/root/");
+        assert!(r.has_errors());
+        assert_eq!(r.media.len(), 3);
+        assert_eq!(
+            r.media.get(&Uid::from_legacy(0x1)).unwrap().target,
+            Target::Command(Command::Learn)
+        );
+        assert_eq!(
+            r.media.get(&Uid::from_legacy(0x123)).unwrap().target,
+            Target::Media(PathBuf::from("/root/foo"))
+        );
+        let problems = r
+            .diagnostics
+            .into_iter()
+            .map(|d| (d.line, d.problem))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            problems,
+            vec![
+                (2, Problem::BadUid("bla".to_owned())),
+                (3, Problem::MissingPath),
+                (4, Problem::DuplicateUid(Uid::from_legacy(0x123), 1)),
+                (8, Problem::BadOption("volume = loud".to_owned())),
+                (9, Problem::BadOption("colour = red".to_owned())),
+                (10, Problem::BadUid("0xzz".to_owned())),
+                (12, Problem::BadUid("[0xabc".to_owned())),
+                (14, Problem::BadCommand("@unknown".to_owned())),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_paths() {
+        let dir = std::env::temp_dir().join(format!("kassette_test_{}", std::process::id()));
+        let root = dir.join("data");
+        std::fs::create_dir_all(root.join("album")).unwrap();
+        std::fs::write(dir.join("outside.ogg"), b"").unwrap();
+
+        let f = std::io::Cursor::new(
+            r"0x1 album
+            0x2 missing.ogg
+            0x3 ../outside.ogg
+            0x4 @learn
+            ",
+        );
+        let mut r = parse_media_definition(f, &root);
+        check_paths(&mut r, &root);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(!r.has_errors());
+        assert_eq!(r.media.len(), 4);
+        assert_eq!(
+            r.diagnostics,
+            vec![
+                Diagnostic {
+                    line: 2,
+                    problem: Problem::PathNotFound(root.join("missing.ogg"))
+                },
+                Diagnostic {
+                    line: 3,
+                    problem: Problem::OutsideDataRoot(root.join("../outside.ogg"))
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(Command::parse("learn"), Some(Command::Learn));
+        assert_eq!(Command::parse("learn now"), None);
+        assert_eq!(
+            Command::parse("sleep-timer 30m"),
+            Some(Command::SleepTimer(Duration::from_secs(30 * 60)))
+        );
+        assert_eq!(
+            Command::parse("sleep-timer  0"),
+            Some(Command::SleepTimer(Duration::from_secs(0)))
+        );
+        assert_eq!(Command::parse("sleep-timer"), None);
+        assert_eq!(Command::parse("sleep-timer soon"), None);
+        assert_eq!(
+            Command::parse("volume -20dB"),
+            Some(Command::Volume(Volume::from_db(-20.0)))
+        );
+        // Legacy volume level
+        assert_eq!(
+            Command::parse("volume 13"),
+            Some(Command::Volume(Volume::from_db(-12.04)))
+        );
+        assert_eq!(
+            Command::parse("volume 0"),
+            Some(Command::Volume(Volume::new(0)))
+        );
+        assert_eq!(
+            Command::parse(&format!("volume {}", crate::player::LEGACY_MAX_VOLUME + 1)),
+            None
+        );
+        assert_eq!(Command::parse("volume -1"), None);
+        assert_eq!(Command::parse("volume"), None);
+        assert_eq!(
+            Command::parse("max-volume -6dB"),
+            Some(Command::MaxVolume(Volume::from_db(-6.0)))
+        );
+        // Legacy volume level
+        assert_eq!(
+            Command::parse("max-volume 13"),
+            Some(Command::MaxVolume(Volume::from_db(-12.04)))
+        );
+        assert_eq!(Command::parse("max-volume 0"), None);
+        assert_eq!(
+            Command::parse(&format!(
+                "max-volume {}",
+                crate::player::LEGACY_MAX_VOLUME + 1
+            )),
+            None
+        );
+        assert_eq!(Command::parse("next"), Some(Command::Next));
+        assert_eq!(Command::parse("Next"), None);
+        assert_eq!(
+            Command::parse("previous-chapter"),
+            Some(Command::PreviousChapter)
+        );
+        assert_eq!(Command::parse("next-chapter 2"), None);
+
+        for command in &[
+            Command::Learn,
+            Command::Shutdown,
+            Command::SleepTimer(Duration::from_secs(1800)),
+            Command::SleepTimer(Duration::from_millis(1500)),
+            Command::Volume(Volume::from_db(-10.0)),
+            Command::Volume(Volume::new(0)),
+            Command::MaxVolume(Volume::from_db(-8.0)),
+            Command::Next,
+            Command::NextChapter,
+            Command::PreviousChapter,
+            Command::Shuffle,
+            Command::Lock,
+        ] {
+            let s = command.to_string();
+            assert_eq!(Command::parse(&s[1..]).as_ref(), Some(command));
+        }
+    }
+
+    #[test]
+    fn test_append_card() {
+        let path = std::env::temp_dir().join(format!("kassette_append_{}", std::process::id()));
+        std::fs::write(&path, "0x1 foo\n[0x2]\npath = bar").unwrap();
+        append_card(&path, Uid::from_legacy(3), "inbox/baz qux").unwrap();
+        append_card(&path, Uid::from_legacy(4), Command::Learn).unwrap();
+
+        let r = parse_media_definition(std::fs::File::open(&path).unwrap(), "/root");
+        std::fs::remove_file(&path).unwrap();
+        assert!(r.diagnostics.is_empty());
+        assert_eq!(r.media.len(), 4);
+        assert_eq!(
+            r.media.get(&Uid::from_legacy(2)).unwrap().media_path(),
+            Some(Path::new("/root/bar"))
+        );
+        assert_eq!(
+            r.media.get(&Uid::from_legacy(3)).unwrap().media_path(),
+            Some(Path::new("/root/inbox/baz qux"))
+        );
         assert_eq!(
-            m.get(&Uid(0xcafe)).unwrap(),
-            &PathBuf::from("/root/cafe.ogg")
+            r.media.get(&Uid::from_legacy(4)).unwrap().target,
+            Target::Command(Command::Learn)
         );
     }
 }
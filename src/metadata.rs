@@ -0,0 +1,88 @@
+/// The value of the first tag with the given key (ignoring case).
+pub fn find_tag<'a>(comments: &'a [(String, String)], key: &str) -> Option<&'a str> {
+    comments
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(key))
+        .map(|(_, v)| v.trim())
+}
+
+/// Descriptive tags of a track.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Metadata {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub track_number: Option<u32>,
+}
+
+impl Metadata {
+    /// Collect the metadata from vorbis comment style tags.
+    pub fn from_comments(comments: &[(String, String)]) -> Self {
+        let tag = |key| {
+            find_tag(comments, key)
+                .filter(|v| !v.is_empty())
+                .map(str::to_owned)
+        };
+        Metadata {
+            title: tag("TITLE"),
+            artist: tag("ARTIST").or_else(|| tag("ALBUMARTIST")),
+            album: tag("ALBUM"),
+            // The number is sometimes followed by the total number of tracks ("3/12").
+            track_number: find_tag(comments, "TRACKNUMBER")
+                .and_then(|n| n.split('/').next())
+                .and_then(|n| n.trim().parse().ok()),
+        }
+    }
+}
+
+impl std::fmt::Display for Metadata {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Artist - Title (Album #3)
+        if let Some(ref artist) = self.artist {
+            write!(f, "{} - ", artist)?;
+        }
+        write!(f, "{}", self.title.as_deref().unwrap_or("Unknown title"))?;
+        match (&self.album, self.track_number) {
+            (Some(album), Some(n)) => write!(f, " ({} #{})", album, n),
+            (Some(album), None) => write!(f, " ({})", album),
+            (None, Some(n)) => write!(f, " (#{})", n),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tags(c: &[(&str, &str)]) -> Vec<(String, String)> {
+        c.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_from_comments() {
+        let m = Metadata::from_comments(&tags(&[
+            ("title", "Kapitel 1"),
+            ("ALBUMARTIST", "Michael Ende"),
+            ("ALBUM", "Momo"),
+            ("TRACKNUMBER", "3/12"),
+            ("TITLE", "Ignored"),
+        ]));
+        assert_eq!(
+            m,
+            Metadata {
+                title: Some("Kapitel 1".to_owned()),
+                artist: Some("Michael Ende".to_owned()),
+                album: Some("Momo".to_owned()),
+                track_number: Some(3),
+            }
+        );
+        assert_eq!(m.to_string(), "Michael Ende - Kapitel 1 (Momo #3)");
+
+        let m = Metadata::from_comments(&tags(&[("ARTIST", " "), ("TRACKNUMBER", "x")]));
+        assert_eq!(m, Metadata::default());
+        assert_eq!(m.to_string(), "Unknown title");
+    }
+}
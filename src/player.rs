@@ -1,65 +1,35 @@
-use miniserde::{Deserialize, Serialize};
-use std::path::Path;
+use crate::chapters::{chapter_at, Chapter};
+use crate::decoder::{DecodeError, Decoder};
+use crate::downmix::Downmix;
+use crate::fade::{Fade, FadeCurve, SmoothGain};
+use crate::filter::{Filter, FilterChain};
+use crate::loudness::{apply_gain, Analyzer};
+use crate::metadata::Metadata;
+use crate::playlist::Playlist;
+use crate::resampler::Resampler;
+use crate::ring_buffer::{ring_buffer, Consumer, Producer};
+use once_cell::sync::Lazy;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 
-struct Resampler {
-    source_sample_counter: u64,
-    source_sample_rate: u64,
-    sink_sample_counter: u64,
-    sink_sample_rate: u64,
-    n_channels: usize,
-}
-
-impl Resampler {
-    fn new(source_sample_rate: u64, sink_sample_rate: u64, n_channels: usize) -> Self {
-        Resampler {
-            source_sample_counter: 0,
-            source_sample_rate,
-            sink_sample_counter: 0,
-            sink_sample_rate,
-            n_channels,
-        }
-    }
-
-    fn resample_nearest(&mut self, input: &[i16]) -> Vec<i16> {
-        assert!(
-            input.len() % self.n_channels == 0,
-            "Invalid input size for channels"
-        );
-        assert!(
-            self.n_channels == 1 || self.n_channels == 2,
-            "Invalid number of input channels"
-        );
-        let mut output = Vec::new();
-        for slice in input.chunks(self.n_channels) {
-            // Output is always two channels
-            let l = slice[0];
-            let r = if self.n_channels == 1 { l } else { slice[1] };
-
-            self.source_sample_counter += 1;
-
-            let new_sink_sample_counter = self.source_sample_counter * self.sink_sample_rate as u64
-                / self.source_sample_rate as u64;
-
-            for _ in 0..(new_sink_sample_counter - self.sink_sample_counter) {
-                output.push(l);
-                output.push(r);
-            }
-            self.sink_sample_counter = new_sink_sample_counter;
-        }
-        output
-    }
-}
-
-use lewton::inside_ogg::OggStreamReader;
-
 const MUTED_BUF: &[i16] = &[0; 1024];
 
 struct AudioSource {
-    stream: OggStreamReader<std::fs::File>,
+    file_path: PathBuf,
+    decoder: Box<dyn Decoder>,
     resampler: Resampler,
-    seek_pos: u64,
-    current_pos: u64,
+    chapters: Vec<Chapter>,
+    metadata: Metadata,
+    /// Linear factor that normalizes the loudness of the track.
+    gain: f32,
+    /// Total number of decode errors.
+    errors: u32,
+    /// Number of decode errors since the last successfully decoded packet.
+    failed_attempts: u32,
+    /// Decoding was given up because the stream is damaged.
+    failed: bool,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -73,79 +43,424 @@ impl PlaybackPos {
     pub fn as_millis(&self) -> u64 {
         self.0.as_millis() as _
     }
+
+    fn from_frame(frame: u64, sample_rate: u64) -> Self {
+        PlaybackPos(Duration::from_micros(1_000_000 * frame / sample_rate))
+    }
+
+    pub fn rewind(&self, time: Duration) -> Self {
+        PlaybackPos(self.0.checked_sub(time).unwrap_or(Duration::from_millis(0)))
+    }
 }
 
 #[derive(Debug)]
 pub enum AudioSourceError {
-    Vorbis(lewton::VorbisError),
-    Io(std::io::Error),
+    Decode(DecodeError),
+    EmptyPlaylist,
+    DecodeThreadFailed,
+    /// There is no known way to mix the channels of the source down to stereo.
+    UnsupportedChannelLayout,
+}
+
+impl std::fmt::Display for AudioSourceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AudioSourceError::Decode(e) => write!(f, "{}", e),
+            AudioSourceError::EmptyPlaylist => write!(f, "Playlist is empty"),
+            AudioSourceError::DecodeThreadFailed => write!(f, "Decode thread failed"),
+            AudioSourceError::UnsupportedChannelLayout => write!(f, "Unsupported channel layout"),
+        }
+    }
+}
+
+impl std::error::Error for AudioSourceError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AudioSourceError::Decode(e) => Some(e),
+            _ => None,
+        }
+    }
 }
 
 impl AudioSource {
     fn new(file_path: impl AsRef<Path>, output_sample_rate: u64) -> Result<Self, AudioSourceError> {
-        let f = std::fs::File::open(file_path).map_err(AudioSourceError::Io)?;
-
-        // Prepare the reading
-        let srr = OggStreamReader::new(f).map_err(AudioSourceError::Vorbis)?;
+        let file_path = file_path.as_ref();
+        let decoder = crate::decoder::open(file_path).map_err(AudioSourceError::Decode)?;
+        Self::with_decoder(file_path, decoder, output_sample_rate)
+    }
 
+    fn with_decoder(
+        file_path: &Path,
+        decoder: Box<dyn Decoder>,
+        output_sample_rate: u64,
+    ) -> Result<Self, AudioSourceError> {
         // Prepare the playback.
-        let n_channels = srr.ident_hdr.audio_channels as usize;
-
-        let resampler = Resampler::new(
-            srr.ident_hdr.audio_sample_rate as _,
-            output_sample_rate,
-            n_channels,
-        );
+        let info = decoder.info();
+        let downmix = Downmix::new(info.channels, decoder.channel_order())
+            .ok_or(AudioSourceError::UnsupportedChannelLayout)?;
+        let resampler = Resampler::new(info.sample_rate as _, output_sample_rate, downmix);
+        let chapters = crate::chapters::parse_chapters(decoder.comments());
+        let metadata = Metadata::from_comments(decoder.comments());
 
         Ok(AudioSource {
-            stream: srr,
+            file_path: file_path.to_owned(),
+            decoder,
             resampler,
-            seek_pos: 0,
-            current_pos: 0,
+            chapters,
+            metadata,
+            gain: 1.0,
+            errors: 0,
+            failed_attempts: 0,
+            failed: false,
         })
     }
 
+    fn set_output_sample_rate(&mut self, sample_rate: u64) {
+        self.resampler.set_sink_sample_rate(sample_rate);
+    }
+
     fn sample_rate(&self) -> u64 {
-        self.stream.ident_hdr.audio_sample_rate as u64
+        self.decoder.info().sample_rate as u64
     }
 
     fn current_pos(&self) -> PlaybackPos {
-        PlaybackPos(Duration::from_micros(
-            1_000_000 * self.current_pos / self.sample_rate(),
-        ))
+        PlaybackPos::from_frame(self.decoder.position(), self.sample_rate())
+    }
+
+    fn duration(&self) -> Option<PlaybackPos> {
+        self.decoder
+            .duration()
+            .map(|d| PlaybackPos::from_frame(d, self.sample_rate()))
     }
 
     fn seek(&mut self, d: PlaybackPos) -> Result<(), AudioSourceError> {
         let pos = d.0.as_micros() as u64 * self.sample_rate() / 1_000_000;
-        self.seek_pos = pos;
-        self.stream
-            .seek_absgp_pg(pos)
-            .map_err(AudioSourceError::Vorbis)?;
-        self.current_pos = pos;
-        Ok(())
+        self.resampler.reset();
+        self.decoder.seek(pos).map_err(AudioSourceError::Decode)
     }
 
+    /// The next chunk of output samples. Returns None at the end of the stream or if decoding has
+    /// been given up after repeated errors.
     fn next_chunk(&mut self) -> Option<Vec<i16>> {
-        match self.stream.read_dec_packet_itl() {
+        if self.failed {
+            return None;
+        }
+        match self.decoder.next_packet() {
             Ok(Some(pck_samples)) => {
-                self.current_pos += pck_samples.len() as u64 / 2;
-                Some(self.resampler.resample_nearest(&pck_samples))
+                self.failed_attempts = 0;
+                Some(self.resampler.resample(&pck_samples))
             }
-            Ok(None) => None,
-            Err(lewton::VorbisError::BadAudio(lewton::audio::AudioReadError::AudioIsHeader)) => {
-                Some(Vec::new())
+            Ok(None) => {
+                let tail = self.resampler.flush();
+                if !tail.is_empty() {
+                    return Some(tail);
+                }
+                if self.errors > 0 {
+                    log!("{} decode errors in {:?}", self.errors, self.file_path);
+                }
+                None
             }
             Err(e) => {
-                log!("Error reading chunk: {}", e);
+                self.errors += 1;
+                self.failed_attempts += 1;
+                // Only the first error in a row is logged, so that a damaged file cannot fill the
+                // log.
+                if self.failed_attempts == 1 {
+                    log!(
+                        "Error decoding {:?} at {}ms: {}",
+                        self.file_path,
+                        self.current_pos().as_millis(),
+                        e
+                    );
+                }
+                if self.failed_attempts > crate::config::MAX_DECODE_RETRIES {
+                    log!(
+                        "Giving up on {:?} after {} decode errors",
+                        self.file_path,
+                        self.errors
+                    );
+                    self.failed = true;
+                    return None;
+                }
+                if self.failed_attempts > 1 {
+                    // Retrying right away was not enough. Skip (further and further) ahead to get
+                    // past the damaged part. For ogg files this resyncs at the next intact page.
+                    let skip = crate::config::DECODE_ERROR_SKIP * (1 << (self.failed_attempts - 2));
+                    let pos = PlaybackPos(self.current_pos().0 + skip);
+                    let _ = self.seek(pos); // A failed seek counts as the next failed attempt.
+                }
                 Some(Vec::new())
             }
         }
     }
 }
 
-const MAX_VOLUME: u8 = 15;
+/// The position in the source after the given number of output frames of the prefetch buffer.
+#[derive(Copy, Clone, Default)]
+struct Marker {
+    frame: u64,
+    position: u64,
+}
+
+#[derive(Default)]
+struct DecodeThreadState {
+    stop: AtomicBool,
+    finished: AtomicBool,
+    failed: AtomicBool,
+}
+
+/// Decode the source into the prefetch buffer until it is exhausted or we are asked to stop.
+fn decode(
+    mut source: AudioSource,
+    mut samples: Producer<i16>,
+    mut markers: Producer<Marker>,
+    state: &DecodeThreadState,
+) -> AudioSource {
+    // Returns false if we should stop instead.
+    let wait = || {
+        std::thread::sleep(crate::config::PREFETCH_POLL_INTERVAL);
+        !state.stop.load(Ordering::Relaxed)
+    };
+    let mut frames_written = 0;
+    while let Some(chunk) = source.next_chunk() {
+        let mut pushed = 0;
+        while pushed < chunk.len() {
+            pushed += samples.push(&chunk[pushed..]);
+            if pushed < chunk.len() && !wait() {
+                return source;
+            }
+        }
+        // Output is always two channels
+        frames_written += chunk.len() as u64 / 2;
+        let marker = Marker {
+            frame: frames_written,
+            position: source.decoder.position(),
+        };
+        // Without room for the marker, the position is interpolated from the previous one.
+        markers.push(&[marker]);
+        if state.stop.load(Ordering::Relaxed) {
+            return source;
+        }
+    }
+    state.failed.store(source.failed, Ordering::Relaxed);
+    state.finished.store(true, Ordering::Release);
+    source
+}
+
+/// An audio source that is decoded ahead of time by a separate thread, so that slow reads (e.g.,
+/// when the sd card is busy) do not interrupt the output.
+struct PrefetchedSource {
+    samples: Consumer<i16>,
+    markers: Consumer<Marker>,
+    state: Arc<DecodeThreadState>,
+    thread: Option<std::thread::JoinHandle<AudioSource>>,
+    frames_read: u64,
+    last_marker: Marker,
+    source_sample_rate: u64,
+    output_sample_rate: u64,
+    file_path: PathBuf,
+    duration: Option<PlaybackPos>,
+    chapters: Vec<Chapter>,
+    metadata: Metadata,
+    gain: f32,
+    underruns: Arc<AtomicUsize>,
+}
+
+impl PrefetchedSource {
+    fn new(source: AudioSource, underruns: Arc<AtomicUsize>) -> Self {
+        let output_sample_rate = source.resampler.sink_sample_rate();
+        let capacity =
+            crate::config::PREFETCH_TIME.as_millis() as usize * output_sample_rate as usize / 1000
+                * 2;
+        let (sample_producer, samples) = ring_buffer(capacity);
+        // There is a marker for every packet, which are hardly ever shorter than that.
+        let (marker_producer, markers) = ring_buffer(capacity / 256);
+        let state = Arc::new(DecodeThreadState::default());
+
+        let source_sample_rate = source.sample_rate();
+        let last_marker = Marker {
+            frame: 0,
+            position: source.decoder.position(),
+        };
+        let file_path = source.file_path.clone();
+        let duration = source.duration();
+        let chapters = source.chapters.clone();
+        let metadata = source.metadata.clone();
+        let gain = source.gain;
+
+        let thread_state = state.clone();
+        let thread = std::thread::Builder::new()
+            .name("decode_thread".to_owned())
+            .spawn(move || decode(source, sample_producer, marker_producer, &thread_state))
+            .unwrap();
+
+        PrefetchedSource {
+            samples,
+            markers,
+            state,
+            thread: Some(thread),
+            frames_read: 0,
+            last_marker,
+            source_sample_rate,
+            output_sample_rate,
+            file_path,
+            duration,
+            chapters,
+            metadata,
+            gain,
+            underruns,
+        }
+    }
+
+    /// Whether the decode thread has ended without finishing the source, i.e., it has panicked.
+    fn crashed(&self) -> bool {
+        let thread_done = self
+            .thread
+            .as_ref()
+            .map(|t| t.is_finished())
+            .unwrap_or(true);
+        thread_done && !self.state.finished.load(Ordering::Acquire)
+    }
+
+    fn failed(&self) -> bool {
+        self.state.failed.load(Ordering::Relaxed) || self.crashed()
+    }
+
+    fn current_pos(&self) -> PlaybackPos {
+        let output_frames = self.frames_read - self.last_marker.frame;
+        let frames = output_frames * self.source_sample_rate / self.output_sample_rate;
+        PlaybackPos::from_frame(self.last_marker.position + frames, self.source_sample_rate)
+    }
 
-#[derive(Copy, Clone, Serialize, Deserialize)]
+    /// Stop decoding ahead, seek and start over from there.
+    fn seek(&mut self, d: PlaybackPos) -> Result<(), AudioSourceError> {
+        self.state.stop.store(true, Ordering::Relaxed);
+        let mut source = self
+            .thread
+            .take()
+            .and_then(|t| t.join().ok())
+            .ok_or(AudioSourceError::DecodeThreadFailed)?;
+        let res = source.seek(d);
+        *self = PrefetchedSource::new(source, self.underruns.clone());
+        res
+    }
+
+    /// The next chunk of output samples. If the decode thread cannot keep up, a chunk of silence
+    /// is returned instead. Returns None at the end of the source.
+    fn next_chunk(&mut self) -> Option<Vec<i16>> {
+        const CHUNK_SAMPLES: usize = 2048;
+
+        // Give the decode thread a moment to catch up before the output runs dry.
+        let deadline = std::time::Instant::now() + crate::config::PREFETCH_WAIT_TIME;
+        while self.samples.len() == 0 {
+            if self.state.finished.load(Ordering::Acquire) || self.crashed() {
+                if self.samples.len() == 0 {
+                    return None;
+                }
+                break;
+            }
+            if std::time::Instant::now() >= deadline {
+                self.underruns.fetch_add(1, Ordering::Relaxed);
+                return Some(MUTED_BUF.to_vec());
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        let mut chunk = Vec::with_capacity(CHUNK_SAMPLES);
+        self.samples.pop(&mut chunk, CHUNK_SAMPLES);
+        self.frames_read += chunk.len() as u64 / 2;
+        while let Some(marker) = self.markers.peek() {
+            if marker.frame > self.frames_read {
+                break;
+            }
+            self.last_marker = marker;
+            self.markers.skip(1);
+        }
+        Some(chunk)
+    }
+}
+
+impl Drop for PrefetchedSource {
+    fn drop(&mut self) {
+        // The thread exits on its own.
+        self.state.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// A snapshot of what is being played.
+#[derive(Clone, Debug)]
+pub struct NowPlaying {
+    pub file_path: PathBuf,
+    pub metadata: Metadata,
+    /// Index of the track in the playlist.
+    pub track: usize,
+    pub tracks: usize,
+    pub chapter: Option<usize>,
+    pub position: PlaybackPos,
+    pub duration: Option<PlaybackPos>,
+    pub playing: bool,
+}
+
+impl NowPlaying {
+    /// Fraction of the track that has been played, if its duration is known.
+    pub fn progress(&self) -> Option<f32> {
+        let duration = self.duration?.0.as_millis();
+        if duration == 0 {
+            return None;
+        }
+        Some((self.position.0.as_millis() as f32 / duration as f32).min(1.0))
+    }
+}
+
+impl std::fmt::Display for NowPlaying {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.metadata.title.is_some() {
+            write!(f, "{}", self.metadata)?;
+        } else {
+            write!(f, "{:?}", self.file_path)?;
+        }
+        write!(f, ", track {}/{}", self.track + 1, self.tracks)?;
+        if let Some(chapter) = self.chapter {
+            write!(f, ", chapter {}", chapter + 1)?;
+        }
+        write!(f, ", at {}s", self.position.0.as_secs())?;
+        if let (Some(duration), Some(progress)) = (self.duration, self.progress()) {
+            write!(
+                f,
+                " of {}s ({:.0}%)",
+                duration.0.as_secs(),
+                progress * 100.0
+            )?;
+        }
+        if !self.playing {
+            write!(f, ", paused")?;
+        }
+        Ok(())
+    }
+}
+
+pub const MAX_VOLUME: u8 = crate::config::VOLUME_STEPS;
+
+/// Size of a volume step in dB
+const VOLUME_STEP_DB: f32 =
+    (crate::config::MAX_VOLUME_DB - crate::config::MIN_VOLUME_DB) / (MAX_VOLUME - 1) as f32;
+
+/// Size of a volume step in dB in older versions, which had 16 levels.
+pub const LEGACY_VOLUME_STEP_DB: f32 = 6.02;
+pub const LEGACY_MAX_VOLUME: u8 = 15;
+
+/// Fixed point (1.15) factors of all volume levels
+static VOLUME_GAINS: Lazy<Vec<i32>> = Lazy::new(|| {
+    (0..=MAX_VOLUME)
+        .map(|amt| match Volume::new(amt).db() {
+            Some(db) => (10f32.powf(db / 20.0) * (1 << 15) as f32).round() as i32,
+            None => 0,
+        })
+        .collect()
+});
+
+/// A volume level between 0 (silent) and MAX_VOLUME. The audible levels are evenly spaced in dB.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Volume {
     amt: u8,
 }
@@ -159,12 +474,55 @@ impl Default for Volume {
 }
 
 impl Volume {
-    fn new(amt: u8) -> Self {
+    pub fn new(amt: u8) -> Self {
         assert!(amt <= MAX_VOLUME);
         Volume { amt }
     }
+
+    /// The audible level that is closest to the given gain in dB.
+    pub fn from_db(db: f32) -> Self {
+        let steps = ((db - crate::config::MIN_VOLUME_DB) / VOLUME_STEP_DB).round();
+        Volume {
+            amt: (steps.max(0.0) as u8 + 1).min(MAX_VOLUME),
+        }
+    }
+
+    /// The level closest to a level of older versions, where 0 was silent as well.
+    pub fn from_legacy(amt: u8) -> Self {
+        match amt {
+            0 => Volume::new(0),
+            amt => {
+                Self::from_db(-LEGACY_VOLUME_STEP_DB * LEGACY_MAX_VOLUME.saturating_sub(amt) as f32)
+            }
+        }
+    }
+
+    /// The gain in dB, or None if the volume is silent.
+    pub fn db(&self) -> Option<f32> {
+        match self.amt {
+            0 => None,
+            amt => Some(crate::config::MIN_VOLUME_DB + (amt - 1) as f32 * VOLUME_STEP_DB),
+        }
+    }
+
     fn apply(&self, i: i16) -> i16 {
-        i >> (MAX_VOLUME.saturating_sub(self.amt))
+        let s = (i as i32 * VOLUME_GAINS[self.amt as usize]) >> 15;
+        s.max(i16::MIN as i32).min(i16::MAX as i32) as i16
+    }
+
+    /// The linear gain of the volume.
+    fn factor(&self) -> f32 {
+        VOLUME_GAINS[self.amt as usize] as f32 / (1 << 15) as f32
+    }
+}
+
+impl Volume {
+    /// Volume shifted by the given number of steps (within the valid range).
+    fn offset(self, steps: i8) -> Self {
+        let amt = (self.amt as i16 + steps as i16)
+            .max(0)
+            .min(MAX_VOLUME as i16);
+        Volume { amt: amt as u8 }
     }
 }
 
@@ -180,43 +538,257 @@ impl std::ops::SubAssign<u8> for Volume {
 }
 
 enum PlayerState {
-    FadeIn(AudioSource, PlaybackPos),
-    Playing(AudioSource),
-    FadeOut(AudioSource, PlaybackPos),
-    Paused(AudioSource),
+    FadeIn(PrefetchedSource, Fade),
+    Playing(PrefetchedSource),
+    FadeOut(PrefetchedSource, Fade),
+    Paused(PrefetchedSource),
     Idle,
 }
 
+/// The track that follows the current one in the playlist.
+enum NextTrack {
+    Unknown,
+    /// Opened ahead of time so that playback can continue without a gap.
+    Ready(usize, Box<PrefetchedSource>),
+    EndOfPlaylist,
+}
+
+/// Tracks of a playlist with len tracks to try (in order) after the current one.
+fn following_tracks(current: usize, len: usize, looping: bool) -> impl Iterator<Item = usize> {
+    // When looping, try every track (including the current one) once before giving up.
+    let count = if looping {
+        len
+    } else {
+        len.saturating_sub(current + 1)
+    };
+    (1..=count).map(move |i| (current + i) % len)
+}
+
 pub struct Player {
     output: crate::sound::AudioOutput,
     state: PlayerState,
+    next_track: NextTrack,
     volume: Volume,
+    /// Upper bound for the volume, including the offset of the current media
+    max_volume: Volume,
+    playlist: Playlist,
+    track: usize,
+    looping: bool,
+    volume_offset: i8,
+    fade_time: Duration,
+    fade_curve: FadeCurve,
+    /// Gain of the volume, which follows volume changes smoothly
+    volume_gain: SmoothGain,
+    filters: FilterChain,
+    analyzer: Analyzer,
+    underruns: Arc<AtomicUsize>,
 }
 
 impl Player {
     pub fn new(output: crate::sound::AudioOutput, volume: Volume) -> Self {
         Player {
+            filters: FilterChain::new(output.sample_rate()),
             output,
             state: PlayerState::Idle,
+            next_track: NextTrack::Unknown,
             volume,
+            max_volume: Volume::new(MAX_VOLUME),
+            playlist: Playlist::default(),
+            track: 0,
+            looping: false,
+            volume_offset: 0,
+            fade_time: crate::config::FADE_TIME,
+            fade_curve: crate::config::FADE_CURVE,
+            volume_gain: SmoothGain::new(volume.factor()),
+            analyzer: Analyzer::new(),
+            underruns: Arc::new(AtomicUsize::new(0)),
         }
     }
-    pub fn volume(&mut self) -> &mut Volume {
-        &mut self.volume
+    pub fn volume(&self) -> Volume {
+        self.volume
+    }
+
+    /// Change the volume, but not beyond the maximum.
+    pub fn set_volume(&mut self, volume: Volume) {
+        self.volume = volume.min(self.max_volume);
+    }
+
+    pub fn max_volume(&self) -> Volume {
+        self.max_volume
+    }
+
+    pub fn set_max_volume(&mut self, max_volume: Volume) {
+        self.max_volume = max_volume;
+        self.volume = self.volume.min(max_volume);
+    }
+
+    /// Start over with the first track once the playlist has been played completely.
+    pub fn set_looping(&mut self, looping: bool) {
+        self.looping = looping;
+        self.next_track = NextTrack::Unknown;
+    }
+
+    /// Gain (in dB) that is added to the user volume for the current media. It is rounded to
+    /// whole volume steps.
+    pub fn set_volume_offset(&mut self, offset_db: f32) {
+        self.volume_offset = (offset_db / VOLUME_STEP_DB).round() as i8;
+    }
+
+    pub fn set_fade_time(&mut self, fade_time: Duration) {
+        self.fade_time = fade_time;
+    }
+
+    pub fn set_fade_curve(&mut self, fade_curve: FadeCurve) {
+        self.fade_curve = fade_curve;
+    }
+
+    /// Replace the filters that process the output.
+    pub fn set_filters(&mut self, filters: Vec<Box<dyn Filter>>) {
+        self.filters.set_filters(filters);
+    }
+
+    fn fade_in(&self) -> Fade {
+        Fade::fade_in(self.fade_curve, self.fade_time, self.output.sample_rate())
+    }
+
+    fn fade_out(&self) -> Fade {
+        Fade::fade_out(self.fade_curve, self.fade_time, self.output.sample_rate())
     }
 
-    pub fn load_file(
+    /// Play the earcon at the current volume. This blocks until the earcon has been queued to the
+    /// output.
+    pub fn play_earcon(&mut self, earcon: crate::earcon::Earcon) {
+        let mut samples = earcon.render(self.output.sample_rate());
+        for s in samples.iter_mut() {
+            *s = self.volume.apply(*s);
+        }
+        self.filters.process(&mut samples);
+        self.output.play_buf(&samples);
+    }
+
+    /// Load the given track of the playlist and prepare playback at start_pos (or the beginning
+    /// of the track). If the track does not exist (anymore), we start at the first track instead.
+    /// Similarly, we start at the beginning if the track is shorter than start_pos.
+    pub fn load(
         &mut self,
-        file_path: impl AsRef<Path>,
+        playlist: Playlist,
+        track: usize,
         start_pos: Option<PlaybackPos>,
     ) -> Result<(), AudioSourceError> {
+        let (track, start_pos) = if track < playlist.len() {
+            (track, start_pos)
+        } else {
+            (0, None)
+        };
+        let file_path = playlist.get(track).ok_or(AudioSourceError::EmptyPlaylist)?;
+        let mut source = self.open_source(file_path)?;
+        let end = source.duration();
+        if let Some(start_pos) = start_pos.filter(|p| end.map(|e| p.0 < e.0).unwrap_or(true)) {
+            source.seek(start_pos)?;
+        }
+
+        // The current media is only stopped once the new one is ready, so that a broken card does
+        // not interrupt the playback.
+        self.state = PlayerState::Idle;
+        self.next_track = NextTrack::Unknown;
+        // Nothing is playing right now, so this is the moment to switch the output to the native
+        // rate of the track. Following tracks are resampled if necessary to avoid gaps.
+        self.output.set_sample_rate(source.sample_rate());
+        source.set_output_sample_rate(self.output.sample_rate());
+        self.filters.set_sample_rate(self.output.sample_rate());
+
+        self.state = PlayerState::Paused(self.prefetch(source));
+        self.playlist = playlist;
+        self.track = track;
+        Ok(())
+    }
+
+    fn open_source(&self, file_path: &Path) -> Result<AudioSource, AudioSourceError> {
         let mut source = AudioSource::new(file_path, self.output.sample_rate())?;
+        if let Some(gain) = self
+            .analyzer
+            .track_gain(file_path, source.decoder.comments())
+        {
+            source.gain = gain.factor();
+        }
+        Ok(source)
+    }
 
-        if let Some(start_pos) = start_pos {
-            source.seek(start_pos)?;
+    fn prefetch(&self, source: AudioSource) -> PrefetchedSource {
+        PrefetchedSource::new(source, self.underruns.clone())
+    }
+
+    /// Open the next playable track of the playlist.
+    fn open_next_track(&self) -> NextTrack {
+        for track in following_tracks(self.track, self.playlist.len(), self.looping) {
+            let file_path = self.playlist.get(track).unwrap(); // Always in range
+            match self.open_source(file_path) {
+                Ok(source) => return NextTrack::Ready(track, Box::new(self.prefetch(source))),
+                Err(e) => log!("Skipping track {:?}: {}", file_path, e),
+            }
+        }
+        NextTrack::EndOfPlaylist
+    }
+
+    /// Open the next track ahead of time (if that has not happened yet).
+    fn preload_next_track(&mut self) {
+        if let NextTrack::Unknown = self.next_track {
+            self.next_track = self.open_next_track();
         }
+    }
 
-        self.state = PlayerState::Paused(source);
+    /// Advance to the next playable track of the playlist, if there is any.
+    fn next_source(&mut self) -> Option<PrefetchedSource> {
+        self.preload_next_track();
+        match std::mem::replace(&mut self.next_track, NextTrack::Unknown) {
+            NextTrack::Ready(track, source) => {
+                self.track = track;
+                log!(
+                    "Continuing with track {}: {:?} ({})",
+                    track,
+                    self.playlist.get(track).unwrap(),
+                    source.metadata
+                );
+                Some(*source)
+            }
+            NextTrack::Unknown | NextTrack::EndOfPlaylist => None,
+        }
+    }
+
+    /// Signal that the track has been cut short because it is damaged.
+    fn report_failure(&mut self, source: &PrefetchedSource) {
+        if source.failed() {
+            self.play_earcon(crate::earcon::Earcon::Error);
+        }
+    }
+
+    /// Continue with the next track of the playlist. Playback is paused if it was paused before.
+    pub fn skip(&mut self) {
+        let mut dummy = PlayerState::Idle;
+        std::mem::swap(&mut dummy, &mut self.state);
+        self.state = match dummy {
+            PlayerState::Playing(_) | PlayerState::FadeIn(_, _) => match self.next_source() {
+                Some(s) => PlayerState::FadeIn(s, self.fade_in()),
+                None => PlayerState::Idle,
+            },
+            PlayerState::Paused(_) | PlayerState::FadeOut(_, _) => self
+                .next_source()
+                .map(PlayerState::Paused)
+                .unwrap_or(PlayerState::Idle),
+            PlayerState::Idle => PlayerState::Idle,
+        }
+    }
+
+    /// Start over with the current playlist in random order.
+    pub fn shuffle(&mut self, seed: u64) -> Result<(), AudioSourceError> {
+        let was_playing = self.playing();
+        // The current playlist is kept if the first track of the new order cannot be loaded.
+        let mut playlist = self.playlist.clone();
+        playlist.shuffle(seed);
+        self.load(playlist, 0, None)?;
+        if was_playing {
+            self.play();
+        }
         Ok(())
     }
 
@@ -226,12 +798,7 @@ impl Player {
             | PlayerState::FadeOut(ref mut s, _)
             | PlayerState::Playing(ref mut s)
             | PlayerState::FadeIn(ref mut s, _) => {
-                let seek_pos = PlaybackPos(
-                    s.current_pos()
-                        .0
-                        .checked_sub(time)
-                        .unwrap_or(Duration::from_millis(0)),
-                );
+                let seek_pos = s.current_pos().rewind(time);
                 s.seek(seek_pos)?;
             }
             PlayerState::Idle => {}
@@ -239,14 +806,69 @@ impl Player {
         Ok(())
     }
 
-    pub fn pause(&mut self) {
+    /// Continue at pos in the current track. Playback fades in again from there.
+    fn jump(&mut self, pos: PlaybackPos) -> Result<(), AudioSourceError> {
         let mut dummy = PlayerState::Idle;
         std::mem::swap(&mut dummy, &mut self.state);
+        let res;
         self.state = match dummy {
-            PlayerState::Playing(i) | PlayerState::FadeIn(i, _) => {
-                let pos = i.current_pos();
-                PlayerState::FadeOut(i, pos)
+            PlayerState::Playing(mut s) | PlayerState::FadeIn(mut s, _) => {
+                res = s.seek(pos);
+                PlayerState::FadeIn(s, self.fade_in())
+            }
+            PlayerState::Paused(mut s) | PlayerState::FadeOut(mut s, _) => {
+                res = s.seek(pos);
+                PlayerState::Paused(s)
+            }
+            PlayerState::Idle => {
+                res = Ok(());
+                PlayerState::Idle
             }
+        };
+        res
+    }
+
+    /// Jump to the start of the next chapter of the current track or to the next track if there
+    /// is no further chapter.
+    pub fn next_chapter(&mut self) -> Result<(), AudioSourceError> {
+        let next = self.playback_pos().and_then(|pos| {
+            let chapters = self.chapters();
+            let next = chapter_at(chapters, pos.0).map(|i| i + 1).unwrap_or(0);
+            chapters.get(next).map(|c| PlaybackPos(c.start))
+        });
+        match next {
+            Some(start) => self.jump(start),
+            None => {
+                self.skip();
+                Ok(())
+            }
+        }
+    }
+
+    /// Jump to the start of the current chapter or, if we are close to it already, to the start of
+    /// the previous one. Tracks without chapters are restarted.
+    pub fn previous_chapter(&mut self) -> Result<(), AudioSourceError> {
+        let pos = match self.playback_pos() {
+            Some(pos) => pos,
+            None => return Ok(()),
+        };
+        let chapters = self.chapters();
+        let start = match chapter_at(chapters, pos.0) {
+            Some(i) if pos.0 - chapters[i].start > crate::config::CHAPTER_RESTART_TIME => {
+                chapters[i].start
+            }
+            Some(i) if i > 0 => chapters[i - 1].start,
+            _ => Duration::from_millis(0),
+        };
+        self.jump(PlaybackPos(start))
+    }
+
+    pub fn pause(&mut self) {
+        let mut dummy = PlayerState::Idle;
+        std::mem::swap(&mut dummy, &mut self.state);
+        self.state = match dummy {
+            PlayerState::Playing(i) => PlayerState::FadeOut(i, self.fade_out()),
+            PlayerState::FadeIn(i, fade) => PlayerState::FadeOut(i, fade.reverse()),
             o => o,
         }
     }
@@ -255,11 +877,8 @@ impl Player {
         let mut dummy = PlayerState::Idle;
         std::mem::swap(&mut dummy, &mut self.state);
         self.state = match dummy {
-            PlayerState::Paused(i) => {
-                let pos = i.current_pos();
-                PlayerState::FadeIn(i, pos)
-            }
-            PlayerState::FadeOut(i, pos) => PlayerState::FadeIn(i, pos),
+            PlayerState::Paused(i) => PlayerState::FadeIn(i, self.fade_in()),
+            PlayerState::FadeOut(i, fade) => PlayerState::FadeIn(i, fade.reverse()),
             o => o,
         }
     }
@@ -280,6 +899,14 @@ impl Player {
         }
     }
 
+    pub fn track(&self) -> Option<usize> {
+        if self.idle() {
+            None
+        } else {
+            Some(self.track)
+        }
+    }
+
     pub fn playback_pos(&self) -> Option<PlaybackPos> {
         match self.state {
             PlayerState::Paused(ref s)
@@ -290,70 +917,157 @@ impl Player {
         }
     }
 
+    /// What is currently being played (or paused), if anything.
+    pub fn now_playing(&self) -> Option<NowPlaying> {
+        let source = match self.state {
+            PlayerState::Paused(ref s)
+            | PlayerState::FadeOut(ref s, _)
+            | PlayerState::Playing(ref s)
+            | PlayerState::FadeIn(ref s, _) => s,
+            PlayerState::Idle => return None,
+        };
+        Some(NowPlaying {
+            file_path: source.file_path.clone(),
+            metadata: source.metadata.clone(),
+            track: self.track,
+            tracks: self.playlist.len(),
+            chapter: self.current_chapter(),
+            position: source.current_pos(),
+            duration: source.duration,
+            playing: self.playing(),
+        })
+    }
+
+    /// Number of times that the decoder did not keep up and that the output ran dry (and had to be
+    /// reset), respectively.
+    pub fn underruns(&self) -> (usize, usize) {
+        (
+            self.underruns.load(Ordering::Relaxed),
+            self.output.underruns(),
+        )
+    }
+
+    /// The chapters of the current track.
+    pub fn chapters(&self) -> &[Chapter] {
+        match self.state {
+            PlayerState::Paused(ref s)
+            | PlayerState::FadeOut(ref s, _)
+            | PlayerState::Playing(ref s)
+            | PlayerState::FadeIn(ref s, _) => &s.chapters,
+            PlayerState::Idle => &[],
+        }
+    }
+
+    /// Index of the chapter that is currently played.
+    pub fn current_chapter(&self) -> Option<usize> {
+        chapter_at(self.chapters(), self.playback_pos()?.0)
+    }
+
     pub fn push_samples(&mut self) {
+        /// Returns false if the source is exhausted.
         fn play_chunk(
-            srr: &mut AudioSource,
+            srr: &mut PrefetchedSource,
             output: &mut crate::sound::AudioOutput,
-            volume: Volume,
-        ) -> Option<PlayerState> {
+            volume_gain: &mut SmoothGain,
+            filters: &mut FilterChain,
+            mut fade: Option<&mut Fade>,
+        ) -> bool {
             if let Some(mut pck_samples) = srr.next_chunk() {
-                for s in &mut pck_samples {
-                    *s = volume.apply(*s);
+                for frame in pck_samples.chunks_mut(2) {
+                    let mut gain = srr.gain * volume_gain.next_gain();
+                    if let Some(fade) = fade.as_mut() {
+                        gain *= fade.next_gain();
+                    }
+                    for s in frame {
+                        *s = apply_gain(*s, gain);
+                    }
                 }
+                filters.process(&mut pck_samples);
                 if pck_samples.len() > 0 {
                     output.play_buf(&pck_samples);
                 }
-                None
+                true
             } else {
-                Some(PlayerState::Idle)
+                false
             }
         }
 
-        fn fade_factor(begin: PlaybackPos, current: PlaybackPos) -> f32 {
-            let diff = current
-                .0
-                .checked_sub(begin.0)
-                .unwrap_or(Duration::from_millis(0));
-            (diff.as_millis() as f32 / crate::config::FADE_TIME.as_millis() as f32).min(1.0)
-        }
-
         let mut dummy = PlayerState::Idle;
         std::mem::swap(&mut dummy, &mut self.state);
 
-        self.state = match dummy {
-            PlayerState::FadeIn(mut srr, begin) => {
-                let factor = fade_factor(begin, srr.current_pos());
-                let fade_vol = Volume::new((self.volume.amt as f32 * factor).round() as u8);
+        let volume = self.volume.offset(self.volume_offset).min(self.max_volume);
+        self.volume_gain.set_target(
+            volume.factor(),
+            crate::config::VOLUME_SMOOTHING_TIME,
+            self.output.sample_rate(),
+        );
 
-                if let Some(s) = play_chunk(&mut srr, &mut self.output, fade_vol) {
-                    s
+        self.state = match dummy {
+            PlayerState::FadeIn(mut srr, mut fade) => {
+                if !play_chunk(
+                    &mut srr,
+                    &mut self.output,
+                    &mut self.volume_gain,
+                    &mut self.filters,
+                    Some(&mut fade),
+                ) {
+                    self.report_failure(&srr);
+                    // The fade in is cut short, but the next track starts at full volume anyway.
+                    self.next_source()
+                        .map(PlayerState::Playing)
+                        .unwrap_or(PlayerState::Idle)
+                } else if fade.finished() {
+                    PlayerState::Playing(srr)
                 } else {
-                    if factor >= 1.0 {
-                        PlayerState::Playing(srr)
-                    } else {
-                        PlayerState::FadeIn(srr, begin)
-                    }
+                    PlayerState::FadeIn(srr, fade)
                 }
             }
-            PlayerState::FadeOut(mut srr, begin) => {
-                let factor = fade_factor(begin, srr.current_pos());
-                let fade_vol = Volume::new((self.volume.amt as f32 * (1.0 - factor)).round() as u8);
-
-                if let Some(s) = play_chunk(&mut srr, &mut self.output, fade_vol) {
-                    s
+            PlayerState::FadeOut(mut srr, mut fade) => {
+                if !play_chunk(
+                    &mut srr,
+                    &mut self.output,
+                    &mut self.volume_gain,
+                    &mut self.filters,
+                    Some(&mut fade),
+                ) {
+                    self.report_failure(&srr);
+                    self.next_source()
+                        .map(PlayerState::Paused)
+                        .unwrap_or(PlayerState::Idle)
+                } else if fade.finished() {
+                    PlayerState::Paused(srr)
                 } else {
-                    if factor >= 1.0 {
-                        PlayerState::Paused(srr)
-                    } else {
-                        PlayerState::FadeOut(srr, begin)
-                    }
+                    PlayerState::FadeOut(srr, fade)
                 }
             }
             PlayerState::Playing(mut srr) => {
-                if let Some(s) = play_chunk(&mut srr, &mut self.output, self.volume) {
-                    s
-                } else {
+                if play_chunk(
+                    &mut srr,
+                    &mut self.output,
+                    &mut self.volume_gain,
+                    &mut self.filters,
+                    None,
+                ) {
+                    // There are enough samples queued now to open the next track without
+                    // risking an underrun.
+                    self.preload_next_track();
                     PlayerState::Playing(srr)
+                } else {
+                    self.report_failure(&srr);
+                    match self.next_source() {
+                        Some(mut next) => {
+                            // Samples of the next track follow immediately without a gap.
+                            play_chunk(
+                                &mut next,
+                                &mut self.output,
+                                &mut self.volume_gain,
+                                &mut self.filters,
+                                None,
+                            );
+                            PlayerState::Playing(next)
+                        }
+                        None => PlayerState::Idle,
+                    }
                 }
             }
             s @ PlayerState::Paused(_) | s @ PlayerState::Idle => {
@@ -369,33 +1083,229 @@ mod test {
     use super::*;
 
     #[test]
-    fn test_two_channel_resample() {
-        let mut r = Resampler::new(1, 1, 2);
-        assert_eq!(&r.resample_nearest(&[1, 2])[..], &[1, 2]);
-        assert_eq!(&r.resample_nearest(&[1, 2, 2, 3])[..], &[1, 2, 2, 3]);
+    fn test_following_tracks() {
+        assert_eq!(
+            following_tracks(0, 3, false).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert_eq!(following_tracks(2, 3, false).count(), 0);
+        assert_eq!(
+            following_tracks(1, 3, true).collect::<Vec<_>>(),
+            vec![2, 0, 1]
+        );
+        assert_eq!(following_tracks(0, 1, true).collect::<Vec<_>>(), vec![0]);
+        assert_eq!(following_tracks(0, 0, true).count(), 0);
     }
 
     #[test]
-    fn test_two_channel_resample_lower() {
-        let mut r = Resampler::new(2, 1, 2);
-        assert_eq!(&r.resample_nearest(&[1, 1, 2, 2])[..], &[2, 2]);
-        assert_eq!(&r.resample_nearest(&[1, 2])[..], &[]);
+    fn test_seek_position() {
+        for &channels in &[1, 2] {
+            let file = crate::decoder::vorbis_file(channels, 400);
+            let path = std::env::temp_dir().join(format!(
+                "kassette_seek_{}_{}.ogg",
+                channels,
+                std::process::id()
+            ));
+            std::fs::write(&path, file).unwrap();
+            let mut reference = AudioSource::new(&path, 44100).unwrap();
+            let mut source = AudioSource::new(&path, 44100).unwrap();
+            std::fs::remove_file(&path).unwrap();
+
+            // All packets but the first one have 128 frames.
+            let frames = 399 * 128;
+            assert_eq!(
+                source.duration().unwrap().as_millis(),
+                frames * 1000 / 44100
+            );
+            let mut samples = Vec::new();
+            while let Some(chunk) = reference.next_chunk() {
+                samples.extend(chunk);
+            }
+            assert_eq!(samples.len() as u64, 2 * frames);
+
+            source.seek(PlaybackPos::from_millis(100)).unwrap();
+            assert_eq!(source.current_pos().as_millis(), 100);
+            // 100ms are 4410 frames and the output is always stereo.
+            let chunk = source.next_chunk().unwrap();
+            assert!(!chunk.is_empty());
+            assert_eq!(chunk[..], samples[2 * 4410..2 * 4410 + chunk.len()]);
+            assert_eq!(
+                source.current_pos().as_millis(),
+                (4410 + chunk.len() as u64 / 2) * 1000 / 44100
+            );
+        }
     }
 
     #[test]
-    fn test_two_channel_resample_higher() {
-        let mut r = Resampler::new(1, 2, 2);
-        assert_eq!(&r.resample_nearest(&[1, 1])[..], &[1, 1, 1, 1]);
-        assert_eq!(
-            &r.resample_nearest(&[1, 2, 3, 4])[..],
-            &[1, 2, 1, 2, 3, 4, 3, 4]
-        );
+    fn test_channel_layouts() {
+        for &(channels, supported) in &[(6, true), (9, false)] {
+            let path = std::env::temp_dir().join(format!(
+                "kassette_layout_{}_{}.ogg",
+                channels,
+                std::process::id()
+            ));
+            std::fs::write(&path, crate::decoder::vorbis_file(channels, 2)).unwrap();
+            let source = AudioSource::new(&path, 44100);
+            std::fs::remove_file(&path).unwrap();
+            match source {
+                Ok(mut source) => {
+                    assert!(supported);
+                    let mut len = 0;
+                    while let Some(chunk) = source.next_chunk() {
+                        len += chunk.len();
+                    }
+                    // The first packet only primes the decoder.
+                    assert_eq!(len, 2 * 128);
+                }
+                Err(AudioSourceError::UnsupportedChannelLayout) => assert!(!supported),
+                Err(e) => panic!("Unexpected error: {:?}", e),
+            }
+        }
+    }
+
+    /// Fails to decode the packets in the given range.
+    struct DamagedDecoder {
+        position: u64,
+        damaged: std::ops::Range<u64>,
+    }
+
+    impl Decoder for DamagedDecoder {
+        fn info(&self) -> crate::decoder::StreamInfo {
+            crate::decoder::StreamInfo {
+                sample_rate: 1000,
+                channels: 1,
+            }
+        }
+
+        fn next_packet(&mut self) -> Result<Option<Vec<i16>>, DecodeError> {
+            if self.damaged.contains(&self.position) {
+                return Err(DecodeError::UnsupportedFormat);
+            }
+            if self.position >= 10_000 {
+                return Ok(None);
+            }
+            self.position += 10;
+            Ok(Some(vec![1; 10]))
+        }
+
+        fn seek(&mut self, frame: u64) -> Result<(), DecodeError> {
+            self.position = frame;
+            Ok(())
+        }
+
+        fn position(&self) -> u64 {
+            self.position
+        }
+
+        fn duration(&self) -> Option<u64> {
+            Some(10_000)
+        }
+    }
+
+    fn damaged_source(damaged: std::ops::Range<u64>) -> AudioSource {
+        crate::log::init_logger(std::env::temp_dir().join("kassette_test.log"));
+        let decoder = DamagedDecoder {
+            position: 0,
+            damaged,
+        };
+        AudioSource::with_decoder(Path::new("damaged.ogg"), Box::new(decoder), 1000).unwrap()
     }
 
     #[test]
-    fn test_one_channel_resample() {
-        let mut r = Resampler::new(1, 1, 1);
-        assert_eq!(&r.resample_nearest(&[1, 2])[..], &[1, 1, 2, 2]);
-        assert_eq!(&r.resample_nearest(&[1, 2, 3])[..], &[1, 1, 2, 2, 3, 3]);
+    fn test_decode_errors() {
+        // The damaged part is skipped.
+        let mut source = damaged_source(100..250);
+        let mut errors = 0;
+        while let Some(chunk) = source.next_chunk() {
+            if chunk.is_empty() {
+                errors += 1;
+            }
+        }
+        assert!(!source.failed);
+        assert_eq!(source.errors, errors);
+        assert!(errors > 1 && errors <= crate::config::MAX_DECODE_RETRIES);
+
+        // A stream that is broken until the end is given up eventually.
+        let mut source = damaged_source(100..u64::MAX);
+        let mut chunks = 0;
+        while source.next_chunk().is_some() {
+            chunks += 1;
+            assert!(chunks < 100);
+        }
+        assert!(source.failed);
+        assert_eq!(source.errors, crate::config::MAX_DECODE_RETRIES + 1);
+        assert!(source.next_chunk().is_none());
+    }
+
+    #[test]
+    fn test_prefetch() {
+        let underruns = Arc::new(AtomicUsize::new(0));
+        let mut source = PrefetchedSource::new(damaged_source(0..0), underruns.clone());
+        assert_eq!(source.current_pos().as_millis(), 0);
+        assert!(source.next_chunk().is_some());
+
+        source.seek(PlaybackPos::from_millis(5000)).unwrap();
+        assert_eq!(source.current_pos().as_millis(), 5000);
+        let mut frames = 0;
+        while let Some(chunk) = source.next_chunk() {
+            frames += chunk.len() / 2;
+        }
+        assert!(frames >= 5000);
+        assert_eq!(source.current_pos().as_millis(), 10_000);
+        assert!(!source.failed());
+        assert!(source.next_chunk().is_none());
+
+        let mut source = PrefetchedSource::new(damaged_source(100..u64::MAX), underruns);
+        while source.next_chunk().is_some() {}
+        assert!(source.failed());
+        // The decoder skipped ahead while trying to recover.
+        assert!(source.current_pos().as_millis() >= 100);
+    }
+
+    #[test]
+    fn test_volume() {
+        assert_eq!(Volume::new(0).apply(i16::MAX), 0);
+        assert_eq!(Volume::new(MAX_VOLUME).apply(i16::MIN), i16::MIN);
+        assert_eq!(Volume::new(MAX_VOLUME).apply(12345), 12345);
+        assert_eq!(Volume::new(1).db(), Some(crate::config::MIN_VOLUME_DB));
+        // The levels are evenly spaced in dB.
+        for amt in 1..MAX_VOLUME {
+            let (a, b) = (Volume::new(amt), Volume::new(amt + 1));
+            assert!((b.db().unwrap() - a.db().unwrap() - VOLUME_STEP_DB).abs() < 1e-4);
+            let ratio = b.apply(i16::MAX) as f32 / a.apply(i16::MAX) as f32;
+            assert!((20.0 * ratio.log10() - VOLUME_STEP_DB).abs() < 0.1);
+            assert_eq!(Volume::from_db(a.db().unwrap()), a);
+        }
+        assert_eq!(Volume::from_db(-100.0), Volume::new(1));
+        assert_eq!(Volume::from_db(10.0), Volume::new(MAX_VOLUME));
+        assert_eq!(Volume::from_legacy(0), Volume::new(0));
+        assert_eq!(Volume::from_legacy(LEGACY_MAX_VOLUME), Volume::from_db(0.0));
+        assert_eq!(Volume::from_legacy(13), Volume::from_db(-12.04));
+    }
+
+    #[test]
+    fn test_now_playing() {
+        let source = PrefetchedSource::new(damaged_source(0..0), Arc::new(AtomicUsize::new(0)));
+        let mut now_playing = NowPlaying {
+            file_path: source.file_path.clone(),
+            metadata: source.metadata.clone(),
+            track: 1,
+            tracks: 3,
+            chapter: None,
+            position: PlaybackPos::from_millis(2500),
+            duration: source.duration,
+            playing: false,
+        };
+        assert_eq!(now_playing.progress(), Some(0.25));
+        assert_eq!(
+            now_playing.to_string(),
+            "\"damaged.ogg\", track 2/3, at 2s of 10s (25%), paused"
+        );
+
+        now_playing.metadata.title = Some("Intro".to_owned());
+        now_playing.duration = None;
+        now_playing.playing = true;
+        assert_eq!(now_playing.progress(), None);
+        assert_eq!(now_playing.to_string(), "Intro, track 2/3, at 2s");
     }
 }
@@ -0,0 +1,209 @@
+use std::cmp::Ordering;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+/// An ordered list of media files that is played back for a single card.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Playlist {
+    tracks: Vec<PathBuf>,
+}
+
+impl Playlist {
+    /// Build the playlist for a media definition target, which can be a single media file, a
+    /// directory (tracks are played in natural sort order) or an .m3u playlist file.
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        let tracks = if path.is_dir() {
+            read_dir_sorted(path)?
+        } else if is_m3u(path) {
+            let f = std::fs::File::open(path)?;
+            parse_m3u(f, path.parent().unwrap_or_else(|| Path::new("/")))
+        } else {
+            vec![path.to_owned()]
+        };
+        Ok(Playlist { tracks })
+    }
+
+    pub fn len(&self) -> usize {
+        self.tracks.len()
+    }
+
+    pub fn get(&self, track: usize) -> Option<&Path> {
+        self.tracks.get(track).map(|p| p.as_path())
+    }
+
+    /// Randomly reorder the tracks. The same seed always results in the same order.
+    pub fn shuffle(&mut self, seed: u64) {
+        // xorshift64*, which is plenty random for shuffling songs.
+        let mut state = seed | 1;
+        let mut next = move || {
+            state ^= state >> 12;
+            state ^= state << 25;
+            state ^= state >> 27;
+            state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+        };
+        for i in (1..self.tracks.len()).rev() {
+            let j = (next() % (i as u64 + 1)) as usize;
+            self.tracks.swap(i, j);
+        }
+    }
+}
+
+fn is_m3u(path: &Path) -> bool {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some(e) => e.eq_ignore_ascii_case("m3u") || e.eq_ignore_ascii_case("m3u8"),
+        None => false,
+    }
+}
+
+fn read_dir_sorted(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut names = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') {
+            continue;
+        }
+        let path = entry.path();
+        if path.is_file() && crate::decoder::is_playable(&path) {
+            names.push(name);
+        }
+    }
+    names.sort_by(|l, r| natural_cmp(l, r));
+    Ok(names.into_iter().map(|n| dir.join(n)).collect())
+}
+
+/// Parse a (possibly extended) m3u playlist. Relative entries are resolved relative to `base`.
+fn parse_m3u(src: impl std::io::Read, base: &Path) -> Vec<PathBuf> {
+    BufReader::new(src)
+        .lines()
+        .map_while(Result::ok)
+        .filter_map(|l| {
+            let l = l.trim();
+            if l.is_empty() || l.starts_with('#') {
+                None
+            } else {
+                Some(base.join(l))
+            }
+        })
+        .collect()
+}
+
+/// Compare strings such that embedded numbers are ordered by value, i.e., "track2" < "track10".
+pub fn natural_cmp(l: &str, r: &str) -> Ordering {
+    fn split_number(s: &str) -> (&str, &str) {
+        let end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        s.split_at(end)
+    }
+
+    let mut l = l;
+    let mut r = r;
+    loop {
+        let (lc, rc) = match (l.chars().next(), r.chars().next()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(lc), Some(rc)) => (lc, rc),
+        };
+        if lc.is_ascii_digit() && rc.is_ascii_digit() {
+            let (ln, lrest) = split_number(l);
+            let (rn, rrest) = split_number(r);
+            let ln_trimmed = ln.trim_start_matches('0');
+            let rn_trimmed = rn.trim_start_matches('0');
+            let ord = ln_trimmed
+                .len()
+                .cmp(&rn_trimmed.len())
+                .then_with(|| ln_trimmed.cmp(rn_trimmed))
+                .then_with(|| ln.len().cmp(&rn.len()));
+            if ord != Ordering::Equal {
+                return ord;
+            }
+            l = lrest;
+            r = rrest;
+        } else {
+            let ord = lc
+                .to_lowercase()
+                .cmp(rc.to_lowercase())
+                .then_with(|| lc.cmp(&rc));
+            if ord != Ordering::Equal {
+                return ord;
+            }
+            l = &l[lc.len_utf8()..];
+            r = &r[rc.len_utf8()..];
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_natural_cmp() {
+        assert_eq!(natural_cmp("track2.ogg", "track10.ogg"), Ordering::Less);
+        assert_eq!(natural_cmp("track10.ogg", "track2.ogg"), Ordering::Greater);
+        assert_eq!(natural_cmp("01 foo.ogg", "2 bar.ogg"), Ordering::Less);
+        assert_eq!(natural_cmp("a.ogg", "B.ogg"), Ordering::Less);
+        assert_eq!(natural_cmp("cd1/2", "cd1/2"), Ordering::Equal);
+        assert_eq!(natural_cmp("part", "part1"), Ordering::Less);
+
+        let mut names = vec![
+            "10.ogg",
+            "9.ogg",
+            "1.ogg",
+            "Chapter 3.ogg",
+            "chapter 12.ogg",
+        ];
+        names.sort_by(|l, r| natural_cmp(l, r));
+        assert_eq!(
+            names,
+            vec![
+                "1.ogg",
+                "9.ogg",
+                "10.ogg",
+                "Chapter 3.ogg",
+                "chapter 12.ogg"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_shuffle() {
+        let tracks = (0..20)
+            .map(|i| PathBuf::from(i.to_string()))
+            .collect::<Vec<_>>();
+        let mut p1 = Playlist {
+            tracks: tracks.clone(),
+        };
+        let mut p2 = p1.clone();
+        p1.shuffle(42);
+        p2.shuffle(42);
+        assert_eq!(p1, p2);
+        assert_ne!(p1.tracks, tracks);
+
+        let mut sorted = p1.tracks.clone();
+        sorted.sort_by(|l, r| natural_cmp(&l.to_string_lossy(), &r.to_string_lossy()));
+        assert_eq!(sorted, tracks);
+    }
+
+    #[test]
+    fn test_parse_m3u() {
+        let f = std::io::Cursor::new(
+            r"#EXTM3U
+            #EXTINF:123, Some title
+            01.ogg
+
+            sub/02.ogg
+            /absolute/03.ogg
+            ",
+        );
+        assert_eq!(
+            parse_m3u(f, Path::new("/root/album")),
+            vec![
+                PathBuf::from("/root/album/01.ogg"),
+                PathBuf::from("/root/album/sub/02.ogg"),
+                PathBuf::from("/absolute/03.ogg"),
+            ]
+        );
+    }
+}
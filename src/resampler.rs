@@ -0,0 +1,404 @@
+use crate::downmix::Downmix;
+use std::f64::consts::PI;
+
+/// How the samples between the ones of the source are computed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+// Only one of them is selected in the config.
+#[allow(dead_code)]
+pub enum Algorithm {
+    /// Repeat or drop samples. Cheap, but causes audible aliasing.
+    Nearest,
+    /// Interpolate linearly between neighbouring samples.
+    Linear,
+    /// Band-limited interpolation with a windowed sinc filter.
+    Sinc,
+}
+
+/// Number of source frames on either side of an output frame that the sinc filter uses.
+const SINC_HALF_WIDTH: usize = 16;
+/// Upper bound for the number of precomputed filter phases. If the ratio of the sample rates needs
+/// more, the position of an output frame is rounded to the nearest phase.
+const MAX_PHASES: u64 = 512;
+/// Shape of the kaiser window of the sinc filter. Larger values attenuate the stop band more
+/// strongly, but widen the transition band.
+const KAISER_BETA: f64 = 6.0;
+/// Cutoff of the sinc filter relative to the lower of the two nyquist frequencies.
+const CUTOFF: f64 = 0.95;
+
+/// Converts interleaved samples to stereo output at another sample rate.
+pub struct Resampler {
+    algorithm: Algorithm,
+    source_sample_counter: u64,
+    source_sample_rate: u64,
+    sink_sample_counter: u64,
+    sink_sample_rate: u64,
+    downmix: Downmix,
+    /// Frames that are still needed to interpolate the next output frames. The stream is preceded
+    /// by enough silence to fill the first filter.
+    history: Vec<[f32; 2]>,
+    /// Index of the first frame of the history, counting the silence before the stream.
+    history_start: u64,
+    /// Coefficients of the interpolation filter: width coefficients for each phase.
+    filter: Vec<f32>,
+    phases: u64,
+    width: usize,
+    /// The end of the stream has been reached and the lagging frames have been output.
+    flushed: bool,
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+/// Modified bessel function of the first kind and order zero
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    for k in 1..50 {
+        term *= x / (2.0 * k as f64);
+        sum += term * term;
+        if term * term < sum * 1e-12 {
+            break;
+        }
+    }
+    sum
+}
+
+fn to_sample(s: f32) -> i16 {
+    s.round().max(i16::MIN as f32).min(i16::MAX as f32) as i16
+}
+
+/// The impulse response of the interpolation at a distance of x source frames.
+fn kernel(algorithm: Algorithm, x: f64, cutoff: f64) -> f64 {
+    match algorithm {
+        Algorithm::Nearest | Algorithm::Linear => (1.0 - x.abs()).max(0.0),
+        Algorithm::Sinc => {
+            let u = x / SINC_HALF_WIDTH as f64;
+            if u.abs() > 1.0 {
+                return 0.0;
+            }
+            let window = bessel_i0(KAISER_BETA * (1.0 - u * u).sqrt()) / bessel_i0(KAISER_BETA);
+            let y = 2.0 * cutoff * x;
+            let sinc = if y == 0.0 {
+                1.0
+            } else {
+                (PI * y).sin() / (PI * y)
+            };
+            sinc * window
+        }
+    }
+}
+
+impl Resampler {
+    pub fn new(source_sample_rate: u64, sink_sample_rate: u64, downmix: Downmix) -> Self {
+        Self::with_algorithm(
+            crate::config::RESAMPLE_ALGORITHM,
+            source_sample_rate,
+            sink_sample_rate,
+            downmix,
+        )
+    }
+
+    pub fn with_algorithm(
+        algorithm: Algorithm,
+        source_sample_rate: u64,
+        sink_sample_rate: u64,
+        downmix: Downmix,
+    ) -> Self {
+        let width = match algorithm {
+            Algorithm::Nearest | Algorithm::Linear => 2,
+            Algorithm::Sinc => 2 * SINC_HALF_WIDTH,
+        };
+        // Output frames only fall on this many different positions between two source frames.
+        let phases = (sink_sample_rate / gcd(source_sample_rate, sink_sample_rate)).min(MAX_PHASES);
+        // Cutoff in cycles per source frame
+        let cutoff = 0.5 * CUTOFF * (sink_sample_rate as f64 / source_sample_rate as f64).min(1.0);
+
+        let mut filter = Vec::with_capacity(phases as usize * width);
+        for phase in 0..phases {
+            let offset = phase as f64 / phases as f64 + (width / 2) as f64 - 1.0;
+            let coefficients = (0..width)
+                .map(|i| kernel(algorithm, offset - i as f64, cutoff))
+                .collect::<Vec<_>>();
+            // Normalize every phase to unity gain, so that a constant signal stays constant.
+            let sum = coefficients.iter().sum::<f64>();
+            filter.extend(coefficients.iter().map(|c| (c / sum) as f32));
+        }
+
+        Resampler {
+            algorithm,
+            source_sample_counter: 0,
+            source_sample_rate,
+            sink_sample_counter: 0,
+            sink_sample_rate,
+            downmix,
+            history: vec![[0.0; 2]; width / 2 - 1],
+            history_start: 0,
+            filter,
+            phases,
+            width,
+            flushed: false,
+        }
+    }
+
+    pub fn sink_sample_rate(&self) -> u64 {
+        self.sink_sample_rate
+    }
+
+    pub fn set_sink_sample_rate(&mut self, sink_sample_rate: u64) {
+        if sink_sample_rate != self.sink_sample_rate {
+            *self = Self::with_algorithm(
+                self.algorithm,
+                self.source_sample_rate,
+                sink_sample_rate,
+                self.downmix.clone(),
+            );
+        }
+    }
+
+    /// Forget the previous input, e.g., after seeking in the source.
+    pub fn reset(&mut self) {
+        self.source_sample_counter = 0;
+        self.sink_sample_counter = 0;
+        self.history.clear();
+        self.history.resize(self.width / 2 - 1, [0.0; 2]);
+        self.history_start = 0;
+        self.flushed = false;
+    }
+
+    /// Convert the next interleaved samples of the source. Interpolating algorithms lag behind the
+    /// input by a few frames.
+    pub fn resample(&mut self, input: &[i16]) -> Vec<i16> {
+        assert!(
+            input.len() % self.downmix.channels() == 0,
+            "Invalid input size for channels"
+        );
+        match self.algorithm {
+            // Nothing to interpolate
+            _ if self.source_sample_rate == self.sink_sample_rate => self.resample_nearest(input),
+            Algorithm::Nearest => self.resample_nearest(input),
+            Algorithm::Linear | Algorithm::Sinc => self.resample_filtered(input),
+        }
+    }
+
+    /// Output the frames that are still lagging behind at the end of the stream. The filter is
+    /// completed with silence after the last frame of the source.
+    pub fn flush(&mut self) -> Vec<i16> {
+        if self.flushed {
+            return Vec::new();
+        }
+        self.flushed = true;
+        match self.algorithm {
+            // No lag
+            _ if self.source_sample_rate == self.sink_sample_rate => Vec::new(),
+            Algorithm::Nearest => Vec::new(),
+            Algorithm::Linear | Algorithm::Sinc => {
+                let silence = vec![0; self.width / 2 * self.downmix.channels()];
+                self.resample_filtered(&silence)
+            }
+        }
+    }
+
+    fn resample_nearest(&mut self, input: &[i16]) -> Vec<i16> {
+        let mut output = Vec::new();
+        for slice in input.chunks(self.downmix.channels()) {
+            // Output is always two channels
+            let [l, r] = self.downmix.frame(slice);
+            let (l, r) = (to_sample(l), to_sample(r));
+
+            self.source_sample_counter += 1;
+
+            let new_sink_sample_counter =
+                self.source_sample_counter * self.sink_sample_rate / self.source_sample_rate;
+
+            for _ in 0..(new_sink_sample_counter - self.sink_sample_counter) {
+                output.push(l);
+                output.push(r);
+            }
+            self.sink_sample_counter = new_sink_sample_counter;
+        }
+        output
+    }
+
+    fn resample_filtered(&mut self, input: &[i16]) -> Vec<i16> {
+        let downmix = &self.downmix;
+        self.history.extend(
+            input
+                .chunks(downmix.channels())
+                .map(|slice| downmix.frame(slice)),
+        );
+        let available = self.history_start + self.history.len() as u64;
+
+        let mut output = Vec::new();
+        loop {
+            // Position of the output frame in source frames
+            let pos = self.sink_sample_counter * self.source_sample_rate;
+            let mut first = pos / self.sink_sample_rate;
+            let remainder = pos % self.sink_sample_rate;
+            let mut phase =
+                (remainder * self.phases + self.sink_sample_rate / 2) / self.sink_sample_rate;
+            if phase == self.phases {
+                first += 1;
+                phase = 0;
+            }
+            if first + self.width as u64 > available {
+                break;
+            }
+
+            let frames = &self.history[(first - self.history_start) as usize..][..self.width];
+            let coefficients = &self.filter[phase as usize * self.width..][..self.width];
+            let mut frame = [0.0f32; 2];
+            for (c, f) in coefficients.iter().zip(frames) {
+                frame[0] += c * f[0];
+                frame[1] += c * f[1];
+            }
+            for s in frame.iter() {
+                output.push(to_sample(*s));
+            }
+            self.sink_sample_counter += 1;
+        }
+
+        // Drop the frames that precede the filter of the next output frame.
+        let next = self.sink_sample_counter * self.source_sample_rate / self.sink_sample_rate;
+        let obsolete = ((next - self.history_start) as usize).min(self.history.len());
+        self.history.drain(..obsolete);
+        self.history_start += obsolete as u64;
+        output
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::decoder::ChannelOrder;
+
+    fn channels(n: usize) -> Downmix {
+        Downmix::new(n, ChannelOrder::Vorbis).unwrap()
+    }
+
+    fn sine(frequency: f64, sample_rate: u64, frames: usize) -> Vec<i16> {
+        (0..frames)
+            .map(|i| {
+                (10000.0 * (2.0 * PI * frequency * i as f64 / sample_rate as f64).sin()).round()
+                    as i16
+            })
+            .collect()
+    }
+
+    fn rms(samples: &[i16]) -> f64 {
+        let sum = samples.iter().map(|&s| s as f64 * s as f64).sum::<f64>();
+        (sum / samples.len() as f64).sqrt()
+    }
+
+    #[test]
+    fn test_two_channel_resample() {
+        let mut r = Resampler::new(1, 1, channels(2));
+        assert_eq!(&r.resample_nearest(&[1, 2])[..], &[1, 2]);
+        assert_eq!(&r.resample_nearest(&[1, 2, 2, 3])[..], &[1, 2, 2, 3]);
+    }
+
+    #[test]
+    fn test_two_channel_resample_lower() {
+        let mut r = Resampler::new(2, 1, channels(2));
+        assert_eq!(&r.resample_nearest(&[1, 1, 2, 2])[..], &[2, 2]);
+        assert_eq!(&r.resample_nearest(&[1, 2])[..], &[]);
+    }
+
+    #[test]
+    fn test_two_channel_resample_higher() {
+        let mut r = Resampler::new(1, 2, channels(2));
+        assert_eq!(&r.resample_nearest(&[1, 1])[..], &[1, 1, 1, 1]);
+        assert_eq!(
+            &r.resample_nearest(&[1, 2, 3, 4])[..],
+            &[1, 2, 1, 2, 3, 4, 3, 4]
+        );
+    }
+
+    #[test]
+    fn test_one_channel_resample() {
+        let mut r = Resampler::new(1, 1, channels(1));
+        assert_eq!(&r.resample_nearest(&[1, 2])[..], &[1, 1, 2, 2]);
+        assert_eq!(&r.resample_nearest(&[1, 2, 3])[..], &[1, 1, 2, 2, 3, 3]);
+    }
+
+    #[test]
+    fn test_multichannel_resample() {
+        let mut r = Resampler::new(1, 1, channels(6));
+        assert_eq!(&r.resample(&[0, 0, 0, 0, 0, 1000])[..], &[0, 0]);
+        let mut r = Resampler::new(1, 1, channels(3));
+        assert_eq!(&r.resample(&[100, 0, 0, 0, 100, 0])[..], &[59, 0, 41, 41]);
+    }
+
+    #[test]
+    fn test_linear_resample() {
+        let mut r = Resampler::with_algorithm(Algorithm::Linear, 1, 2, channels(1));
+        assert_eq!(
+            &r.resample(&[0, 100, 200, 300])[..],
+            &[0, 0, 50, 50, 100, 100, 150, 150, 200, 200, 250, 250]
+        );
+        assert_eq!(&r.resample(&[400])[..], &[300, 300, 350, 350]);
+        assert_eq!(&r.flush()[..], &[400, 400, 200, 200]);
+        assert_eq!(&r.flush()[..], &[]);
+
+        let mut r = Resampler::with_algorithm(Algorithm::Linear, 3, 2, channels(2));
+        assert_eq!(&r.resample(&[0, 0, 30, -30, 60, -60])[..], &[0, 0, 45, -45]);
+        r.reset();
+        assert_eq!(&r.resample(&[0, 0, 30, -30])[..], &[0, 0]);
+        r.set_sink_sample_rate(3);
+        assert_eq!(&r.resample(&[0, 0, 30, -30])[..], &[0, 0, 30, -30]);
+    }
+
+    #[test]
+    fn test_sinc_resample() {
+        // A tone in the pass band is preserved.
+        let mut r = Resampler::with_algorithm(Algorithm::Sinc, 48000, 44100, channels(1));
+        let output = r.resample(&sine(1000.0, 48000, 4800));
+        let reference = sine(1000.0, 44100, 4410);
+        let max_error = output
+            .iter()
+            .step_by(2)
+            .zip(&reference)
+            // The start is faded in from silence.
+            .skip(SINC_HALF_WIDTH)
+            .map(|(&a, &b)| (a as i32 - b as i32).abs())
+            .max()
+            .unwrap();
+        assert!(max_error <= 10, "max error {}", max_error);
+
+        // A tone above the nyquist frequency of the output is removed instead of aliased.
+        let mut r = Resampler::with_algorithm(Algorithm::Sinc, 48000, 22050, channels(1));
+        let output = r.resample(&sine(18000.0, 48000, 4800));
+        assert!(rms(&output[100..]) < 50.0, "rms {}", rms(&output[100..]));
+        let mut r = Resampler::with_algorithm(Algorithm::Nearest, 48000, 22050, channels(1));
+        assert!(rms(&r.resample(&sine(18000.0, 48000, 4800))) > 5000.0);
+    }
+
+    #[test]
+    fn test_chunked_resample() {
+        let input = sine(440.0, 22050, 2000)
+            .into_iter()
+            .zip(sine(1000.0, 22050, 2000))
+            .flat_map(|(l, r)| vec![l, r])
+            .collect::<Vec<_>>();
+        for &algorithm in &[Algorithm::Linear, Algorithm::Sinc] {
+            let mut r = Resampler::with_algorithm(algorithm, 22050, 44100, channels(2));
+            let expected = r.resample(&input);
+
+            let mut r = Resampler::with_algorithm(algorithm, 22050, 44100, channels(2));
+            let mut output = Vec::new();
+            for chunk in input.chunks(2 * 37) {
+                output.extend(r.resample(chunk));
+            }
+            assert_eq!(output, expected);
+            // Lagging behind by less than the filter
+            assert!(output.len() / 2 >= 2 * (2000 - SINC_HALF_WIDTH));
+            // Until the end of the stream, where everything is output.
+            output.extend(r.flush());
+            assert_eq!(output.len() / 2, 2 * 2000);
+        }
+    }
+}
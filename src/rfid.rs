@@ -6,27 +6,76 @@ use std::path::Path;
 use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, TrySendError};
 use std::time::Duration;
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
-pub struct Uid(pub u32);
+pub const MAX_UID_LEN: usize = 10;
+
+/// Card uid. Depending on the card type, these are 4, 7 or 10 bytes long.
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Uid {
+    len: u8,
+    bytes: [u8; MAX_UID_LEN],
+}
+
+impl Uid {
+    pub fn new(bytes: &[u8]) -> Option<Self> {
+        if bytes.is_empty() || bytes.len() > MAX_UID_LEN {
+            return None;
+        }
+        let mut uid = Uid {
+            len: bytes.len() as u8,
+            bytes: [0; MAX_UID_LEN],
+        };
+        uid.bytes[..bytes.len()].copy_from_slice(bytes);
+        Some(uid)
+    }
+
+    /// Uids used to be stored as a single (big endian) u32, which is only correct for 4 byte uids.
+    pub fn from_legacy(val: u32) -> Self {
+        Uid::new(&val.to_be_bytes()).unwrap() // 4 bytes are always valid
+    }
+
+    /// Parse the colon separated hex form as produced by Display, e.g. "04:A2:1B:3C:5D:6E:7F".
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut bytes = Vec::new();
+        for b in s.split(':') {
+            if b.is_empty() || b.len() > 2 {
+                return None;
+            }
+            bytes.push(u8::from_str_radix(b, 16).ok()?);
+        }
+        match bytes.len() {
+            4 | 7 | 10 => Uid::new(&bytes),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+}
 
 impl std::fmt::Display for Uid {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:02X} ", (self.0 >> 24) & 0xff)?;
-        write!(f, "{:02X} ", (self.0 >> 16) & 0xff)?;
-        write!(f, "{:02X} ", (self.0 >> 8) & 0xff)?;
-        write!(f, "{:02X}", (self.0 >> 0) & 0xff)?;
+        for (i, b) in self.as_bytes().iter().enumerate() {
+            if i != 0 {
+                write!(f, ":")?;
+            }
+            write!(f, "{:02X}", b)?;
+        }
         Ok(())
     }
 }
 
+impl std::fmt::Debug for Uid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Uid({})", self)
+    }
+}
+
 impl From<rfid_rs::Uid> for Uid {
     fn from(other: rfid_rs::Uid) -> Self {
-        let mut val = 0;
-        for b in other.bytes {
-            val = val << 8;
-            val += b as u32;
-        }
-        Uid(val)
+        // The reader never reports more than MAX_UID_LEN bytes (or less than 4).
+        let len = other.bytes.len().min(MAX_UID_LEN);
+        Uid::new(&other.bytes[..len]).unwrap()
     }
 }
 
@@ -190,7 +239,7 @@ impl RfidReader {
                 }
                 (None, true) => {
                     if let Some(uid) = self.read_uid() {
-                        previous = Some(uid.clone());
+                        previous = Some(uid);
                         return Some(RfidEvent::Added(uid));
                     }
                 }
@@ -199,3 +248,42 @@ impl RfidReader {
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_uid() {
+        let uid = Uid::parse("04:a2:1B:3c:5d:6e:7f").unwrap();
+        assert_eq!(uid.as_bytes(), &[0x04, 0xa2, 0x1b, 0x3c, 0x5d, 0x6e, 0x7f]);
+        assert_eq!(uid.to_string(), "04:A2:1B:3C:5D:6E:7F");
+        assert_eq!(Uid::parse(&uid.to_string()), Some(uid));
+        assert_eq!(
+            Uid::parse("de:ad:be:ef"),
+            Some(Uid::from_legacy(0xdeadbeef))
+        );
+        assert_eq!(
+            Uid::parse("1:2:3:4:5:6:7:8:9:a").unwrap().as_bytes().len(),
+            10
+        );
+
+        assert_eq!(Uid::parse(""), None);
+        assert_eq!(Uid::parse("de:ad:be"), None);
+        assert_eq!(Uid::parse("de:ad::be:ef"), None);
+        assert_eq!(Uid::parse("de:ad:be:eff"), None);
+        assert_eq!(Uid::parse("de:ad:be:xx"), None);
+    }
+
+    #[test]
+    fn test_uid_no_collision() {
+        // Only differ in the leading bytes, which used to be shifted out of the u32
+        let a = Uid::parse("01:02:03:04:05:06:07").unwrap();
+        let b = Uid::parse("08:02:03:04:05:06:07").unwrap();
+        assert_ne!(a, b);
+        assert_ne!(
+            Uid::parse("00:00:00:00:00:00:00"),
+            Uid::parse("00:00:00:00")
+        );
+    }
+}
@@ -0,0 +1,163 @@
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+struct Shared<T> {
+    buf: Box<[UnsafeCell<T>]>,
+    // Total number of elements that have been written and read. They wrap around, which is fine
+    // because the capacity is a power of two.
+    written: AtomicUsize,
+    read: AtomicUsize,
+}
+
+// The producer only writes to the slots that are not readable and the consumer only reads from
+// the readable ones. Ownership of a slot is handed over by the release/acquire pairs on the
+// counters.
+unsafe impl<T: Send> Sync for Shared<T> {}
+
+impl<T> Shared<T> {
+    fn capacity(&self) -> usize {
+        self.buf.len()
+    }
+
+    fn slot(&self, index: usize) -> *mut T {
+        self.buf[index & (self.capacity() - 1)].get()
+    }
+}
+
+/// The writing half of a single producer, single consumer ring buffer.
+pub struct Producer<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// The reading half of a single producer, single consumer ring buffer.
+pub struct Consumer<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// Create a lock-free ring buffer that holds at least `capacity` elements.
+pub fn ring_buffer<T: Copy + Default>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    let buf = (0..capacity.next_power_of_two())
+        .map(|_| UnsafeCell::new(T::default()))
+        .collect();
+    let shared = Arc::new(Shared {
+        buf,
+        written: AtomicUsize::new(0),
+        read: AtomicUsize::new(0),
+    });
+    (
+        Producer {
+            shared: shared.clone(),
+        },
+        Consumer { shared },
+    )
+}
+
+impl<T: Copy> Producer<T> {
+    /// Number of elements that can be pushed right now.
+    pub fn free(&self) -> usize {
+        let written = self.shared.written.load(Ordering::Relaxed);
+        let read = self.shared.read.load(Ordering::Acquire);
+        self.shared.capacity() - written.wrapping_sub(read)
+    }
+
+    /// Append as many of the items as fit and return their number.
+    pub fn push(&mut self, items: &[T]) -> usize {
+        let written = self.shared.written.load(Ordering::Relaxed);
+        let n = items.len().min(self.free());
+        for (i, item) in items[..n].iter().enumerate() {
+            unsafe { *self.shared.slot(written.wrapping_add(i)) = *item };
+        }
+        self.shared
+            .written
+            .store(written.wrapping_add(n), Ordering::Release);
+        n
+    }
+}
+
+impl<T: Copy> Consumer<T> {
+    /// Number of elements that can be popped right now.
+    pub fn len(&self) -> usize {
+        let written = self.shared.written.load(Ordering::Acquire);
+        let read = self.shared.read.load(Ordering::Relaxed);
+        written.wrapping_sub(read)
+    }
+
+    /// The next element, without removing it.
+    pub fn peek(&self) -> Option<T> {
+        if self.len() == 0 {
+            return None;
+        }
+        let read = self.shared.read.load(Ordering::Relaxed);
+        Some(unsafe { *self.shared.slot(read) })
+    }
+
+    /// Remove up to n elements without reading them.
+    pub fn skip(&mut self, n: usize) {
+        let read = self.shared.read.load(Ordering::Relaxed);
+        let n = self.len().min(n);
+        self.shared
+            .read
+            .store(read.wrapping_add(n), Ordering::Release);
+    }
+
+    /// Remove up to max elements and append them to out. Returns the number of elements.
+    pub fn pop(&mut self, out: &mut Vec<T>, max: usize) -> usize {
+        let read = self.shared.read.load(Ordering::Relaxed);
+        let n = self.len().min(max);
+        out.extend((0..n).map(|i| unsafe { *self.shared.slot(read.wrapping_add(i)) }));
+        self.shared
+            .read
+            .store(read.wrapping_add(n), Ordering::Release);
+        n
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_push_pop() {
+        let (mut p, mut c) = ring_buffer::<i16>(3);
+        assert_eq!(p.free(), 4);
+        assert_eq!(c.peek(), None);
+        assert_eq!(p.push(&[1, 2, 3]), 3);
+        assert_eq!(p.push(&[4, 5]), 1);
+        assert_eq!(c.len(), 4);
+        assert_eq!(c.peek(), Some(1));
+
+        let mut out = Vec::new();
+        assert_eq!(c.pop(&mut out, 3), 3);
+        assert_eq!(out, vec![1, 2, 3]);
+        // Wrap around
+        assert_eq!(p.push(&[5, 6, 7, 8]), 3);
+        assert_eq!(c.pop(&mut out, 10), 4);
+        assert_eq!(out, vec![1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(c.len(), 0);
+        assert_eq!(c.pop(&mut out, 10), 0);
+        p.push(&[9, 10]);
+        c.skip(1);
+        assert_eq!(c.peek(), Some(10));
+    }
+
+    #[test]
+    fn test_threads() {
+        let (mut p, mut c) = ring_buffer::<u32>(64);
+        let producer = std::thread::spawn(move || {
+            let items = (0..10_000).collect::<Vec<u32>>();
+            let mut pos = 0;
+            while pos < items.len() {
+                pos += p.push(&items[pos..(pos + 10).min(items.len())]);
+                std::thread::yield_now();
+            }
+        });
+        let mut out = Vec::new();
+        while out.len() < 10_000 {
+            c.pop(&mut out, 7);
+            std::thread::yield_now();
+        }
+        producer.join().unwrap();
+        assert!(out.iter().enumerate().all(|(i, v)| i as u32 == *v));
+    }
+}
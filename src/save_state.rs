@@ -8,15 +8,91 @@ use std::time::{Duration, SystemTime};
 
 #[derive(Serialize, Deserialize)]
 struct SerPlaybackState {
-    uid: u32,
+    // Written by older versions, which only supported 4 byte uids.
+    #[serde(rename = "uid")]
+    legacy_uid: Option<u32>,
+    card: Option<String>,
+    track: Option<u64>,
     playback_pos: u64,
     stop_time: u64,
 }
 
+impl SerPlaybackState {
+    fn new(uid: Uid, track: usize, playback_pos: PlaybackPos, stop_time: SystemTime) -> Self {
+        SerPlaybackState {
+            legacy_uid: None,
+            card: Some(uid.to_string()),
+            track: Some(track as u64),
+            playback_pos: playback_pos.as_millis(),
+            stop_time: stop_time
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+        }
+    }
+
+    fn stop_time(&self) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_millis(self.stop_time)
+    }
+
+    fn uid(&self) -> Option<Uid> {
+        match (&self.card, self.legacy_uid) {
+            (Some(card), _) => Uid::parse(card),
+            (None, Some(uid)) => Some(Uid::from_legacy(uid)),
+            (None, None) => None,
+        }
+    }
+
+    fn get(&self) -> Option<(Uid, usize, PlaybackPos, SystemTime)> {
+        Some((
+            self.uid()?,
+            self.track.unwrap_or(0) as usize,
+            PlaybackPos::from_millis(self.playback_pos),
+            self.stop_time(),
+        ))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SerVolume {
+    // Written by older versions, which had 16 levels that were 6 dB apart.
+    #[serde(rename = "amt")]
+    legacy_amt: Option<u8>,
+    // Stored in dB, so that it survives changes of the volume steps. None means silent.
+    db: Option<f32>,
+}
+
+impl SerVolume {
+    fn new(volume: Volume) -> Self {
+        SerVolume {
+            legacy_amt: None,
+            db: volume.db(),
+        }
+    }
+
+    fn get(&self) -> Volume {
+        match (self.db, self.legacy_amt) {
+            (Some(db), _) => Volume::from_db(db),
+            (None, Some(amt)) => Volume::from_legacy(amt),
+            (None, None) => Volume::new(0),
+        }
+    }
+}
+
+impl Default for SerVolume {
+    fn default() -> Self {
+        SerVolume::new(Volume::default())
+    }
+}
+
 #[derive(Serialize, Deserialize, Default)]
 pub struct SaveState {
     playback_state: Option<SerPlaybackState>,
-    volume: Volume,
+    // Optional only to be able to read save states written before per-card positions existed.
+    resume_points: Option<Vec<SerPlaybackState>>,
+    volume: SerVolume,
+    // Set by the parents. Missing in older save states.
+    max_volume: Option<SerVolume>,
 }
 
 impl SaveState {
@@ -26,37 +102,197 @@ impl SaveState {
         f.read_to_string(&mut buf).ok()?;
         json::from_str(&buf).ok()
     }
-    pub fn save(&self, f: impl AsRef<Path>) -> std::io::Result<()> {
-        let mut f = File::create(f)?;
+    /// The state is written to a temporary file first, so that the previous state survives if
+    /// the power is cut while saving.
+    pub fn save(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        let path = path.as_ref();
+        let tmp_path = path.with_extension("tmp");
+        let mut f = File::create(&tmp_path)?;
         let buf = json::to_string(self);
         f.write_all(buf.as_bytes())?;
-        Ok(())
-    }
-    pub fn playback_state(&self) -> Option<(Uid, PlaybackPos, SystemTime)> {
-        self.playback_state.as_ref().map(|sbp| {
-            (
-                Uid(sbp.uid),
-                PlaybackPos::from_millis(sbp.playback_pos),
-                SystemTime::UNIX_EPOCH + Duration::from_millis(sbp.stop_time),
-            )
-        })
+        f.sync_all()?;
+        std::fs::rename(tmp_path, path)
     }
-    pub fn set_playback_state(&mut self, playback_pos: Option<(Uid, PlaybackPos, SystemTime)>) {
-        self.playback_state = playback_pos.map(|(uid, playback_pos, stop_time)| SerPlaybackState {
-            uid: uid.0,
-            playback_pos: playback_pos.as_millis() as u64,
-            stop_time: stop_time
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap_or_default()
-                .as_millis() as u64,
+    pub fn playback_state(&self) -> Option<(Uid, usize, PlaybackPos, SystemTime)> {
+        self.playback_state.as_ref().and_then(SerPlaybackState::get)
+    }
+    pub fn set_playback_state(
+        &mut self,
+        playback_pos: Option<(Uid, usize, PlaybackPos, SystemTime)>,
+    ) {
+        if let Some((uid, track, pos, stop_time)) = playback_pos {
+            self.set_resume_point(uid, track, pos, stop_time);
+        }
+        self.playback_state = playback_pos.map(|(uid, track, playback_pos, stop_time)| {
+            SerPlaybackState::new(uid, track, playback_pos, stop_time)
         })
     }
 
+    /// Where playback of the card stopped the last time (if it was played recently enough).
+    pub fn resume_point(&self, uid: Uid) -> Option<(usize, PlaybackPos, SystemTime)> {
+        self.resume_points
+            .iter()
+            .flatten()
+            .chain(self.playback_state.iter())
+            .filter_map(SerPlaybackState::get)
+            .find(|p| p.0 == uid)
+            .map(|(_, track, pos, stop_time)| (track, pos, stop_time))
+    }
+
+    pub fn set_resume_point(
+        &mut self,
+        uid: Uid,
+        track: usize,
+        pos: PlaybackPos,
+        stop_time: SystemTime,
+    ) {
+        self.clear_resume_point(uid);
+        self.resume_points
+            .get_or_insert_with(Vec::new)
+            .push(SerPlaybackState::new(uid, track, pos, stop_time));
+        self.prune_resume_points(SystemTime::now());
+    }
+
+    /// Forget the position of the card, e.g., because it has been played to the end.
+    pub fn clear_resume_point(&mut self, uid: Uid) {
+        if let Some(points) = self.resume_points.as_mut() {
+            points.retain(|p| p.uid() != Some(uid));
+        }
+        if self.playback_state.as_ref().and_then(SerPlaybackState::uid) == Some(uid) {
+            self.playback_state = None;
+        }
+    }
+
+    fn prune_resume_points(&mut self, now: SystemTime) {
+        if let Some(points) = self.resume_points.as_mut() {
+            points.retain(|p| {
+                now.duration_since(p.stop_time()).unwrap_or_default()
+                    <= crate::config::MAX_RESUME_POINT_AGE
+            });
+            // Most recent first, so that the oldest entries are dropped if there are too many.
+            points.sort_by_key(|p| std::cmp::Reverse(p.stop_time));
+            points.truncate(crate::config::MAX_RESUME_POINTS);
+        }
+    }
+
     pub fn volume(&self) -> Volume {
-        self.volume
+        self.volume.get()
     }
 
     pub fn set_volume(&mut self, vol: Volume) {
-        self.volume = vol;
+        self.volume = SerVolume::new(vol);
+    }
+
+    pub fn max_volume(&self) -> Volume {
+        self.max_volume
+            .as_ref()
+            .map(SerVolume::get)
+            .unwrap_or_else(|| Volume::new(crate::player::MAX_VOLUME))
+    }
+
+    pub fn set_max_volume(&mut self, vol: Volume) {
+        self.max_volume = Some(SerVolume::new(vol));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::player::MAX_VOLUME;
+
+    #[test]
+    fn test_resume_points() {
+        let mut s: SaveState = Default::default();
+        let t = SystemTime::now();
+        s.set_resume_point(Uid::from_legacy(1), 2, PlaybackPos::from_millis(1000), t);
+        s.set_resume_point(Uid::from_legacy(2), 0, PlaybackPos::from_millis(500), t);
+        s.set_resume_point(Uid::from_legacy(1), 3, PlaybackPos::from_millis(42), t);
+        let long_uid = Uid::new(&[1, 2, 3, 4, 5, 6, 7]).unwrap();
+        s.set_resume_point(long_uid, 1, PlaybackPos::from_millis(7), t);
+
+        let (track, pos, _) = s.resume_point(Uid::from_legacy(1)).unwrap();
+        assert_eq!((track, pos.as_millis()), (3, 42));
+        let (track, pos, _) = s.resume_point(Uid::from_legacy(2)).unwrap();
+        assert_eq!((track, pos.as_millis()), (0, 500));
+        assert!(s.resume_point(Uid::from_legacy(3)).is_none());
+        let (track, pos, _) = s.resume_point(long_uid).unwrap();
+        assert_eq!((track, pos.as_millis()), (1, 7));
+
+        s.clear_resume_point(Uid::from_legacy(1));
+        assert!(s.resume_point(Uid::from_legacy(1)).is_none());
+    }
+
+    #[test]
+    fn test_prune_resume_points() {
+        let mut s: SaveState = Default::default();
+        let now = SystemTime::now();
+        let old = now - crate::config::MAX_RESUME_POINT_AGE - Duration::from_secs(1);
+        s.set_resume_point(Uid::from_legacy(0), 0, PlaybackPos::from_millis(0), old);
+        assert!(s.resume_point(Uid::from_legacy(0)).is_none());
+
+        for i in 0..crate::config::MAX_RESUME_POINTS as u32 + 1 {
+            let stop_time = now - Duration::from_secs(1000) + Duration::from_secs(i as u64);
+            s.set_resume_point(
+                Uid::from_legacy(i + 1),
+                0,
+                PlaybackPos::from_millis(0),
+                stop_time,
+            );
+        }
+        // The card that was played the longest time ago is forgotten first
+        assert!(s.resume_point(Uid::from_legacy(1)).is_none());
+        assert!(s.resume_point(Uid::from_legacy(2)).is_some());
+    }
+
+    #[test]
+    fn test_load_legacy() {
+        let s: SaveState = json::from_str(
+            r#"{"playback_state":{"uid":1234,"playback_pos":5000,"stop_time":0},"volume":{"amt":7}}"#,
+        )
+        .unwrap();
+        let (uid, track, pos, _) = s.playback_state().unwrap();
+        assert_eq!(
+            (uid, track, pos.as_millis()),
+            (Uid::from_legacy(1234), 0, 5000)
+        );
+        assert!(s.resume_point(Uid::from_legacy(1234)).is_some());
+
+        // Level 7 was -48 dB, which is below MIN_VOLUME_DB and therefore maps onto the quietest
+        // audible level.
+        assert_eq!(s.volume(), Volume::new(1));
+
+        let s2: SaveState = json::from_str(&json::to_string(&s)).unwrap();
+        let (uid, _, _, _) = s2.playback_state().unwrap();
+        assert_eq!(uid, Uid::from_legacy(1234));
+        assert_eq!(s2.volume(), Volume::new(1));
+    }
+
+    #[test]
+    fn test_volume_migration() {
+        let volume = |json: &str| json::from_str::<SaveState>(json).unwrap().volume();
+        assert_eq!(volume(r#"{"volume":{"amt":15}}"#), Volume::new(MAX_VOLUME));
+        assert_eq!(volume(r#"{"volume":{"amt":11}}"#).db(), Some(-24.0));
+        assert_eq!(volume(r#"{"volume":{"amt":0}}"#), Volume::new(0));
+        assert_eq!(volume(r#"{"volume":{"db":-30.5}}"#).db(), Some(-30.0));
+        assert_eq!(volume(r#"{"volume":{"db":null}}"#), Volume::new(0));
+
+        let mut s: SaveState = Default::default();
+        assert_eq!(s.volume(), Volume::default());
+        assert_eq!(s.max_volume(), Volume::new(MAX_VOLUME));
+        s.set_max_volume(Volume::new(10));
+        let s2: SaveState = json::from_str(&json::to_string(&s)).unwrap();
+        assert_eq!(s2.max_volume(), Volume::new(10));
+        let path = std::env::temp_dir().join(format!("kassette_state_{}.json", std::process::id()));
+        s.save(&path).unwrap();
+        assert_eq!(
+            SaveState::load(&path).unwrap().max_volume(),
+            Volume::new(10)
+        );
+        std::fs::remove_file(&path).unwrap();
+        for amt in 0..=MAX_VOLUME {
+            s.set_volume(Volume::new(amt));
+            let s2: SaveState = json::from_str(&json::to_string(&s)).unwrap();
+            assert_eq!(s2.volume(), Volume::new(amt));
+        }
     }
 }
@@ -1,10 +1,47 @@
+use crate::limiter::Limiter;
 use std::time::{Duration, Instant};
 
+const DEFAULT_SAMPLE_RATE: u32 = 44100;
+
+/// Set up the device for 16 bit stereo at the given sample rate. We prefer rates that the hardware
+/// supports natively and do the resampling ourselves. Only if the device does not allow that, alsa
+/// converts to the requested rate. Returns the sample rate that the device expects.
+fn configure(pcm: &alsa::pcm::PCM, sample_rate: u32) -> alsa::Result<u64> {
+    use alsa::pcm::{Access, Format, HwParams};
+    use alsa::ValueOr;
+
+    let set_hw_params = |rate_resample: bool| -> alsa::Result<()> {
+        let hwp = HwParams::any(pcm)?;
+        hwp.set_channels(2)?;
+        hwp.set_rate_resample(rate_resample)?;
+        hwp.set_rate(sample_rate, ValueOr::Nearest)?;
+        hwp.set_format(Format::s16())?;
+        hwp.set_access(Access::RWInterleaved)?;
+        pcm.hw_params(&hwp)
+    };
+    if let Err(e) = set_hw_params(false) {
+        log!("No native sample rate close to {}: {:?}", sample_rate, e);
+        set_hw_params(true)?;
+    }
+
+    // Make sure we don't start the stream too early
+    let hwp = pcm.hw_params_current()?;
+    let swp = pcm.sw_params_current()?;
+    swp.set_start_threshold(hwp.get_buffer_size()? - hwp.get_period_size()?)?;
+    pcm.sw_params(&swp)?;
+
+    Ok(hwp.get_rate()? as u64)
+}
+
 pub struct AudioOutput {
     pcm: alsa::pcm::PCM,
     current_sample_num: u64,
     start_time: Instant,
     sample_rate: u64,
+    /// The rate that was asked for last, which may not be supported by the device.
+    requested_sample_rate: u64,
+    limiter: Limiter,
+    underruns: usize,
 }
 
 impl AudioOutput {
@@ -25,56 +62,75 @@ impl AudioOutput {
             selm.set_playback_volume_all(maxvol).unwrap();
         }
 
-        use alsa::pcm::{Access, Format, HwParams, PCM};
-        use alsa::{Direction, ValueOr};
+        use alsa::pcm::PCM;
+        use alsa::Direction;
 
         // Open default playback device
         let pcm = PCM::new("default", Direction::Playback, false).unwrap();
-
-        let sample_rate = 44100;
-
-        // Set hardware parameters: 44100 Hz / Mono / 16 bit
-        {
-            // TODO: try to supporting setting this for media files?
-            let hwp = HwParams::any(&pcm).unwrap();
-            hwp.set_channels(2).unwrap();
-            hwp.set_rate(sample_rate, ValueOr::Nearest).unwrap();
-            hwp.set_format(Format::s16()).unwrap();
-            hwp.set_access(Access::RWInterleaved).unwrap();
-            pcm.hw_params(&hwp).unwrap();
-        }
-
-        // Make sure we don't start the stream too early
-        {
-            let hwp = pcm.hw_params_current().unwrap();
-            let swp = pcm.sw_params_current().unwrap();
-            swp.set_start_threshold(
-                hwp.get_buffer_size().unwrap() - hwp.get_period_size().unwrap(),
-            )
-            .unwrap();
-            pcm.sw_params(&swp).unwrap();
-        }
+        let sample_rate = configure(&pcm, DEFAULT_SAMPLE_RATE).unwrap();
 
         AudioOutput {
             pcm,
             current_sample_num: 0,
             start_time: Instant::now(),
-            sample_rate: sample_rate as _,
+            sample_rate,
+            requested_sample_rate: DEFAULT_SAMPLE_RATE as _,
+            limiter: Limiter::new(sample_rate),
+            underruns: 0,
         }
     }
 
+    /// Switch to the sample rate of a new source, or the closest one that the device supports.
+    /// Everything that has been played before is drained first, so this should only be called
+    /// while the output is (about to be) silent.
+    pub fn set_sample_rate(&mut self, sample_rate: u64) {
+        if sample_rate == self.sample_rate || sample_rate == self.requested_sample_rate {
+            return;
+        }
+        self.requested_sample_rate = sample_rate;
+        log_err!(
+            "Drain audio output",
+            self.pcm.drain().or_else(|_| self.pcm.drop())
+        );
+        // If the device refuses the rate, we stay at the previous one and resample instead.
+        self.sample_rate = match configure(&self.pcm, sample_rate as u32) {
+            Ok(rate) => rate,
+            Err(e) => {
+                log!("Cannot switch to sample rate {}: {:?}", sample_rate, e);
+                configure(&self.pcm, self.sample_rate as u32).unwrap_or_else(|e| {
+                    log!("Cannot restore sample rate {}: {:?}", self.sample_rate, e);
+                    self.sample_rate
+                })
+            }
+        };
+        log!("Audio output sample rate: {}", self.sample_rate);
+        self.limiter = Limiter::new(self.sample_rate);
+        self.current_sample_num = 0;
+        self.start_time = Instant::now();
+    }
+
     pub fn sample_rate(&self) -> u64 {
         self.sample_rate
     }
 
+    /// Number of times that the output had to be recovered, usually because it ran dry.
+    pub fn underruns(&self) -> usize {
+        self.underruns
+    }
+
     fn recover(&mut self, e: alsa::Error) {
         log!("Trying to recover from error: {:?}", e);
+        self.underruns += 1;
         self.pcm.try_recover(e, false).unwrap(); // Not sure what to do after RECOVERY (!) fails...
         self.current_sample_num = 0;
         self.start_time = Instant::now();
     }
 
     pub fn play_buf(&mut self, buf: &[i16]) {
+        // Nothing reaches the speaker without passing the limiter.
+        let mut buf = buf.to_vec();
+        self.limiter.process(&mut buf);
+
         let write_res = {
             let io = self.pcm.io_i16().unwrap(); // Not sure what to do if this fails...
             io.writei(&buf[..])